@@ -0,0 +1,104 @@
+use crate::{entry::Stamp, WalkTree, WalkTreeError};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// Items of a previous walk, reused for entries whose stamp did not change.
+pub(crate) trait ScanCache<T> {
+    fn load(&mut self) -> HashMap<PathBuf, (Stamp, T)>;
+    /// Stores the nodes of `tree` keyed under `prefix`, relative to it.
+    fn store(&mut self, tree: &WalkTree<T>, prefix: &Path) -> Result<(), WalkTreeError>;
+}
+
+#[cfg(feature = "serde")]
+mod file {
+    use super::*;
+    use crate::WalkTreeBuilder;
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+    use std::{fs, marker::PhantomData};
+
+    impl<T: Serialize + DeserializeOwned + 'static> WalkTreeBuilder<T> {
+        /// Keeps the mapped items and each entry's size, modification time and
+        /// inode in a file at `path`. The next walk reuses the stored item of
+        /// every entry whose metadata did not change instead of calling the
+        /// mapper. Set it after the mapper, as `with_map` and its siblings
+        /// start without a cache.
+        pub fn with_cache(self, path: &Path) -> Self {
+            WalkTreeBuilder {
+                cache: Some(Box::new(FileCache::new(path))),
+                ..self
+            }
+        }
+    }
+
+    /// Bumped whenever the layout of `CacheFile` changes.
+    const VERSION: u32 = 1;
+
+    #[derive(Serialize, Deserialize)]
+    struct CacheFile<E> {
+        version: u32,
+        entries: Vec<E>,
+    }
+
+    /// A cache stored at `path` with postcard.
+    pub(crate) struct FileCache<T> {
+        path: PathBuf,
+        data: PhantomData<fn() -> T>,
+    }
+
+    impl<T> FileCache<T> {
+        pub(crate) fn new(path: &Path) -> Self {
+            FileCache {
+                path: path.to_path_buf(),
+                data: PhantomData,
+            }
+        }
+    }
+
+    impl<T: Serialize + DeserializeOwned> ScanCache<T> for FileCache<T> {
+        fn load(&mut self) -> HashMap<PathBuf, (Stamp, T)> {
+            // A missing, outdated or corrupt cache only means everything gets mapped again.
+            fs::read(&self.path)
+                .ok()
+                .and_then(|bytes| {
+                    postcard::from_bytes::<CacheFile<(PathBuf, Stamp, T)>>(&bytes).ok()
+                })
+                .filter(|file| file.version == VERSION)
+                .map(|file| {
+                    file.entries
+                        .into_iter()
+                        .map(|(path, stamp, data)| (path, (stamp, data)))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+
+        fn store(&mut self, tree: &WalkTree<T>, prefix: &Path) -> Result<(), WalkTreeError> {
+            let entries = tree
+                .map
+                .iter()
+                .filter_map(|(path, node_id)| {
+                    let stamp = tree.stamps.get(path)?;
+                    let key = path.strip_prefix(prefix).ok()?;
+                    Some((key, stamp, tree.arena[*node_id].get()))
+                })
+                .collect();
+            let bytes = postcard::to_allocvec(&CacheFile {
+                version: VERSION,
+                entries,
+            })
+            .map_err(|err| WalkTreeError::Encoding(err.into()))?;
+            // Written next to the cache and renamed, so an interrupted walk never leaves half a file.
+            let mut partial = self.path.clone().into_os_string();
+            partial.push(".partial");
+            fs::write(&partial, bytes)
+                .and_then(|()| fs::rename(&partial, &self.path))
+                .map_err(|source| WalkTreeError::Io {
+                    path: Some(self.path.clone()),
+                    depth: 0,
+                    source,
+                })
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::WalkTree;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// The differences between two trees, as returned by `WalkTree::diff`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    /// Subtrees found unchanged under another path, as `(from, to)`. Their
+    /// descendants are not listed separately.
+    pub moved: Vec<(PathBuf, PathBuf)>,
+}
+
+impl TreeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+            && self.moved.is_empty()
+    }
+}
+
+impl<T: PartialEq> WalkTree<T> {
+    /// Compares the tree to `other`, describing the changes that turn `self` into `other`.
+    /// See `diff_by` for why trees of `EntryInfo` need a comparator.
+    pub fn diff(&self, other: &WalkTree<T>) -> TreeDiff {
+        self.diff_by(other, |a, b| a == b)
+    }
+}
+
+impl<T> WalkTree<T> {
+    /// Like `diff`, with `eq` deciding whether two items are the same.
+    ///
+    /// Entries are matched by path. A removed subtree whose paths and items
+    /// all reappear under a single added path is reported as moved; when
+    /// several added paths qualify, one with the same file name is preferred.
+    ///
+    /// The default `EntryInfo` holds the absolute path, the depth and the
+    /// modification time of its entry, so with `diff` every entry of trees
+    /// walked from different roots is modified and a subtree moved within
+    /// a tree is never found. Comparing only what stays the same across
+    /// walks gives the changes to the contents:
+    ///
+    /// ```
+    /// use walktree::{MemoryFs, WalkTree};
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let fs = MemoryFs::from_paths(["/a/src/lib.rs", "/a/old/x", "/b/src/lib.rs", "/b/new/x"]);
+    /// let walk = |root: &str| {
+    ///     WalkTree::load(Path::new(root))
+    ///         .with_file_system(fs.clone())
+    ///         .walk()
+    ///         .unwrap()
+    /// };
+    /// let (a, b) = (walk("/a"), walk("/b"));
+    /// let diff = a.diff_by(&b, |x, y| x.file_type == y.file_type && x.len == y.len);
+    /// assert!(diff.modified.is_empty());
+    /// assert_eq!(diff.moved, [(PathBuf::from("old"), PathBuf::from("new"))]);
+    /// ```
+    pub fn diff_by<F>(&self, other: &WalkTree<T>, mut eq: F) -> TreeDiff
+    where
+        F: FnMut(&T, &T) -> bool,
+    {
+        let mut diff = TreeDiff::default();
+        for (path, node_id) in self.map.iter() {
+            match other.map.get_by_left(path).map(|id| other.arena[*id].get()) {
+                Some(item) if !eq(self.arena[*node_id].get(), item) => {
+                    diff.modified.push(path.clone())
+                }
+                Some(_) => {}
+                None => diff.removed.push(path.clone()),
+            }
+        }
+        diff.added = other
+            .map
+            .left_values()
+            .filter(|path| !self.map.contains_left(*path))
+            .cloned()
+            .collect();
+        diff.removed.sort();
+        diff.added.sort();
+        diff.modified.sort();
+
+        let removed = diff.removed.iter().map(PathBuf::as_path).collect();
+        let added = diff.added.iter().map(PathBuf::as_path).collect();
+        let is_top =
+            |path: &&PathBuf, set: &HashSet<&Path>| path.parent().is_none_or(|p| !set.contains(p));
+        let candidates = diff
+            .added
+            .iter()
+            .filter(|path| is_top(path, &added))
+            .map(|path| (path, other.relative_subtree(path)))
+            .collect::<Vec<_>>();
+        // Only a subtree with the same relative paths can match, so `eq` is
+        // left for the candidates of that shape, same-named ones first.
+        let mut by_shape = HashMap::<_, Vec<_>>::new();
+        let mut by_name = HashMap::<_, Vec<_>>::new();
+        for (index, (to, subtree)) in candidates.iter().enumerate() {
+            let shape = shape(subtree);
+            by_name
+                .entry((shape.clone(), to.file_name()))
+                .or_default()
+                .push(index);
+            by_shape.entry(shape).or_default().push(index);
+        }
+        let mut taken = vec![false; candidates.len()];
+        let mut moved = Vec::new();
+        for from in diff.removed.iter().filter(|path| is_top(path, &removed)) {
+            let subtree = self.relative_subtree(from);
+            let shape = shape(&subtree);
+            let same_name = by_name.get(&(shape.clone(), from.file_name()));
+            let other_name = by_shape
+                .get(&shape)
+                .into_iter()
+                .flatten()
+                .filter(|index| candidates[**index].0.file_name() != from.file_name());
+            let found = same_name
+                .into_iter()
+                .flatten()
+                .chain(other_name)
+                .find(|index| {
+                    !taken[**index]
+                        && subtree.iter().all(|(rel, item)| {
+                            candidates[**index].1.get(rel).is_some_and(|o| eq(item, o))
+                        })
+                });
+            if let Some(&index) = found {
+                taken[index] = true;
+                moved.push((from.clone(), candidates[index].0.clone()));
+            }
+        }
+        moved.sort();
+
+        let from = moved
+            .iter()
+            .map(|(from, _)| from.as_path())
+            .collect::<HashSet<_>>();
+        let to = moved
+            .iter()
+            .map(|(_, to)| to.as_path())
+            .collect::<HashSet<_>>();
+        diff.removed
+            .retain(|path| !path.ancestors().any(|a| from.contains(a)));
+        diff.added
+            .retain(|path| !path.ancestors().any(|a| to.contains(a)));
+        diff.moved = moved;
+        diff
+    }
+
+    /// Items of the subtree at `path`, keyed by their path relative to it.
+    fn relative_subtree(&self, path: &Path) -> HashMap<PathBuf, &T> {
+        let Some(node_id) = self.map.get_by_left(path) else {
+            return HashMap::new();
+        };
+        node_id
+            .descendants(&self.arena)
+            .filter_map(|id| {
+                let relative = self.get_path_by_node_id(id)?.strip_prefix(path).ok()?;
+                Some((relative.to_path_buf(), self.arena[id].get()))
+            })
+            .collect()
+    }
+}
+
+/// The relative paths of a subtree, in order.
+fn shape<'a, T>(subtree: &'a HashMap<PathBuf, &T>) -> Vec<&'a Path> {
+    let mut paths = subtree.keys().map(PathBuf::as_path).collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
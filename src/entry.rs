@@ -0,0 +1,183 @@
+use crate::{FileSystem, Metadata, RealFs};
+use std::{
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// An entry yielded by the walk, handed to filters, mappers and sort functions.
+///
+/// Mirrors `walkdir::DirEntry` so that both the sequential and the parallel
+/// backend can produce it.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    file_type: FileKind,
+    depth: usize,
+    follow_link: bool,
+    /// The filesystem the entry was read from, `None` for the disk.
+    fs: Option<Arc<dyn FileSystem>>,
+}
+
+impl PartialEq for DirEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+            && self.file_type == other.file_type
+            && self.depth == other.depth
+            && self.follow_link == other.follow_link
+    }
+}
+
+impl Eq for DirEntry {}
+
+impl DirEntry {
+    pub(crate) fn new(
+        path: PathBuf,
+        file_type: FileKind,
+        depth: usize,
+        follow_link: bool,
+        fs: Option<Arc<dyn FileSystem>>,
+    ) -> Self {
+        DirEntry {
+            path,
+            file_type,
+            depth,
+            follow_link,
+            fs,
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+    /// Whether the path itself is a symbolic link, even when the link was followed.
+    pub fn path_is_symlink(&self) -> bool {
+        self.follow_link || self.file_type.is_symlink()
+    }
+    /// Reads the metadata of the entry, following the link if it was followed during the walk.
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        let fs = self.fs.as_deref().unwrap_or(&RealFs);
+        if self.follow_link {
+            fs.metadata(&self.path)
+        } else {
+            fs.symlink_metadata(&self.path)
+        }
+    }
+    pub fn file_type(&self) -> FileKind {
+        self.file_type
+    }
+    pub fn file_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or(self.path.as_os_str())
+    }
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+impl From<walkdir::DirEntry> for DirEntry {
+    fn from(entry: walkdir::DirEntry) -> Self {
+        DirEntry {
+            file_type: entry.file_type().into(),
+            depth: entry.depth(),
+            follow_link: entry.path_is_symlink() && !entry.file_type().is_symlink(),
+            path: entry.into_path(),
+            fs: None,
+        }
+    }
+}
+
+impl From<&walkdir::DirEntry> for DirEntry {
+    fn from(entry: &walkdir::DirEntry) -> Self {
+        DirEntry {
+            path: entry.path().to_path_buf(),
+            file_type: entry.file_type().into(),
+            depth: entry.depth(),
+            follow_link: entry.path_is_symlink() && !entry.file_type().is_symlink(),
+            fs: None,
+        }
+    }
+}
+
+/// Metadata used to tell whether an entry changed between two walks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+    ino: u64,
+}
+
+impl Stamp {
+    pub(crate) fn of(entry: &DirEntry) -> Option<Stamp> {
+        let metadata = entry.metadata().ok()?;
+        Some(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            ino: metadata.ino(),
+        })
+    }
+}
+
+/// Node type of a tree walked without `WalkTreeBuilder::with_map`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntryInfo {
+    pub path: PathBuf,
+    pub file_type: FileKind,
+    pub depth: usize,
+    /// Size in bytes as reported by the entry's metadata, `0` if it could not be read.
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+impl FileKind {
+    pub fn is_file(self) -> bool {
+        self == FileKind::File
+    }
+    pub fn is_dir(self) -> bool {
+        self == FileKind::Dir
+    }
+    pub fn is_symlink(self) -> bool {
+        self == FileKind::Symlink
+    }
+}
+
+impl From<fs::FileType> for FileKind {
+    fn from(file_type: fs::FileType) -> Self {
+        if file_type.is_dir() {
+            FileKind::Dir
+        } else if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        }
+    }
+}
+
+impl From<DirEntry> for EntryInfo {
+    fn from(entry: DirEntry) -> Self {
+        let metadata = entry.metadata().ok();
+        EntryInfo {
+            file_type: entry.file_type(),
+            depth: entry.depth(),
+            len: metadata.as_ref().map_or(0, |m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()),
+            path: entry.into_path(),
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use crate::FileKind;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Where a walk reads directories and metadata from, set with
+/// `WalkTreeBuilder::with_file_system`.
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    /// Lists the directory at `path`, with the type of each entry as a
+    /// symlink rather than its target.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<io::Result<(PathBuf, FileKind)>>>;
+    /// Metadata of `path`, following symlinks.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// Metadata of `path` itself, even if it is a symlink.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// The absolute form of `path` with every symlink resolved.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Reads the whole file at `path`, which is how ignore files are read.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+}
+
+/// Metadata of an entry as reported by a `FileSystem`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    file_type: FileKind,
+    len: u64,
+    modified: Option<SystemTime>,
+    dev: u64,
+    ino: u64,
+}
+
+impl Metadata {
+    pub fn new(file_type: FileKind, len: u64) -> Self {
+        Metadata {
+            file_type,
+            len,
+            modified: None,
+            dev: 0,
+            ino: 0,
+        }
+    }
+    pub fn with_modified(self, modified: SystemTime) -> Self {
+        Metadata {
+            modified: Some(modified),
+            ..self
+        }
+    }
+    /// Sets the device and inode numbers, which tell symlink loops, file
+    /// system boundaries and replaced files apart.
+    pub fn with_file_id(self, dev: u64, ino: u64) -> Self {
+        Metadata { dev, ino, ..self }
+    }
+    pub fn file_type(&self) -> FileKind {
+        self.file_type
+    }
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+    pub fn is_symlink(&self) -> bool {
+        self.file_type.is_symlink()
+    }
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Fails like `std::fs::Metadata::modified` when there is no modification time.
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        self.modified.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "modification time unavailable")
+        })
+    }
+    pub fn dev(&self) -> u64 {
+        self.dev
+    }
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+}
+
+impl From<fs::Metadata> for Metadata {
+    fn from(metadata: fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let (dev, ino) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (dev, ino) = (0, 0);
+        Metadata {
+            file_type: metadata.file_type().into(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            dev,
+            ino,
+        }
+    }
+}
+
+/// The disk, through `std::fs`. Walks use it unless told otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<io::Result<(PathBuf, FileKind)>>> {
+        Ok(fs::read_dir(path)?
+            .map(|entry| entry.and_then(|e| Ok((e.path(), e.file_type()?.into()))))
+            .collect())
+    }
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(Metadata::from)
+    }
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(Metadata::from)
+    }
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+/// Symlinks followed while resolving one path before giving up, as on Linux.
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Clone)]
+enum MemoryNode {
+    Dir,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+}
+
+/// A filesystem held in memory, for walks that must not depend on the disk.
+///
+/// Paths are absolute, relative ones being taken from `/`, and parent
+/// directories are created as entries are added:
+///
+/// ```
+/// use walktree::{MemoryFs, WalkTree};
+/// use std::path::Path;
+///
+/// let fs = MemoryFs::from_paths(["/src/lib.rs", "/src/bin/", "/README.md"])
+///     .with_symlink("/docs", "src");
+/// let tree = WalkTree::load(Path::new("/"))
+///     .with_file_system(fs)
+///     .walk()
+///     .unwrap();
+/// assert_eq!(tree.map.len(), 6);
+/// ```
+///
+/// Entries have no modification time, and each one added gets a new inode,
+/// even when it replaces another at the same path.
+#[derive(Debug, Clone)]
+pub struct MemoryFs {
+    nodes: BTreeMap<PathBuf, (u64, MemoryNode)>,
+    next_ino: u64,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem, with only the `/` directory.
+    pub fn new() -> Self {
+        MemoryFs {
+            nodes: BTreeMap::from([(PathBuf::from("/"), (1, MemoryNode::Dir))]),
+            next_ino: 2,
+        }
+    }
+    /// Builds a filesystem from a list of paths, directories marked by a
+    /// trailing `/` and files left empty.
+    pub fn from_paths<I, S>(paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        paths.into_iter().fold(Self::new(), |fs, path| {
+            let path = path.as_ref();
+            if path.ends_with('/') {
+                fs.with_dir(path)
+            } else {
+                fs.with_file(path, "")
+            }
+        })
+    }
+    pub fn with_dir(self, path: impl AsRef<Path>) -> Self {
+        self.with_node(path.as_ref(), MemoryNode::Dir)
+    }
+    pub fn with_file(self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) -> Self {
+        self.with_node(path.as_ref(), MemoryNode::File(contents.into()))
+    }
+    /// Adds a symlink to `target`, which is resolved relative to the link's
+    /// directory unless it is absolute.
+    pub fn with_symlink(self, path: impl AsRef<Path>, target: impl AsRef<Path>) -> Self {
+        self.with_node(
+            path.as_ref(),
+            MemoryNode::Symlink(target.as_ref().to_path_buf()),
+        )
+    }
+
+    fn with_node(mut self, path: &Path, node: MemoryNode) -> Self {
+        let path = normalize(path);
+        for dir in path.ancestors().skip(1) {
+            if !self.nodes.contains_key(dir) {
+                let ino = self.take_ino();
+                self.nodes.insert(dir.to_path_buf(), (ino, MemoryNode::Dir));
+            }
+        }
+        let ino = self.take_ino();
+        self.nodes.insert(path, (ino, node));
+        self
+    }
+    fn take_ino(&mut self) -> u64 {
+        self.next_ino += 1;
+        self.next_ino - 1
+    }
+
+    /// Resolves the symlinks along `path`, and the one it names if `follow` is set.
+    fn resolve(&self, path: &Path, follow: bool) -> io::Result<(PathBuf, &(u64, MemoryNode))> {
+        let mut resolved = PathBuf::from("/");
+        let mut pending = names(path);
+        pending.reverse();
+        let mut symlinks = 0;
+        while let Some(name) = pending.pop() {
+            if name == ".." {
+                resolved.pop();
+                continue;
+            }
+            let next = resolved.join(&name);
+            let Some((_, node)) = self.nodes.get(&next) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} does not exist", path.display()),
+                ));
+            };
+            match node {
+                MemoryNode::Symlink(target) if follow || !pending.is_empty() => {
+                    symlinks += 1;
+                    if symlinks > MAX_SYMLINKS {
+                        return Err(io::Error::other(format!(
+                            "too many levels of symbolic links in {}",
+                            path.display()
+                        )));
+                    }
+                    if target.is_absolute() {
+                        resolved = PathBuf::from("/");
+                    }
+                    pending.extend(names(target).into_iter().rev());
+                }
+                MemoryNode::File(_) if pending.iter().any(|name| name != "..") => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("{} is not a directory", next.display()),
+                    ));
+                }
+                _ => resolved = next,
+            }
+        }
+        let node = &self.nodes[&resolved];
+        Ok((resolved, node))
+    }
+
+    fn metadata_of(&self, path: &Path, follow: bool) -> io::Result<Metadata> {
+        let (_, (ino, node)) = self.resolve(path, follow)?;
+        let metadata = match node {
+            MemoryNode::Dir => Metadata::new(FileKind::Dir, 0),
+            MemoryNode::File(contents) => Metadata::new(FileKind::File, contents.len() as u64),
+            MemoryNode::Symlink(target) => {
+                Metadata::new(FileKind::Symlink, target.as_os_str().len() as u64)
+            }
+        };
+        Ok(metadata.with_file_id(0, *ino))
+    }
+}
+
+impl FileSystem for MemoryFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<io::Result<(PathBuf, FileKind)>>> {
+        let (dir, (_, node)) = self.resolve(path, true)?;
+        if !matches!(node, MemoryNode::Dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", path.display()),
+            ));
+        }
+        Ok(self
+            .nodes
+            .range(dir.clone()..)
+            .skip(1)
+            .take_while(|(child, _)| child.starts_with(&dir))
+            .filter(|(child, _)| child.parent() == Some(&dir))
+            .map(|(child, (_, node))| {
+                let file_type = match node {
+                    MemoryNode::Dir => FileKind::Dir,
+                    MemoryNode::File(_) => FileKind::File,
+                    MemoryNode::Symlink(_) => FileKind::Symlink,
+                };
+                Ok((path.join(child.file_name().unwrap()), file_type))
+            })
+            .collect())
+    }
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.metadata_of(path, true)
+    }
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.metadata_of(path, false)
+    }
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.resolve(path, true).map(|(path, _)| path)
+    }
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.resolve(path, true)? {
+            (_, (_, MemoryNode::File(contents))) => Ok(contents.clone()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!("{} is a directory", path.display()),
+            )),
+        }
+    }
+}
+
+/// The names along `path`, keeping `..` for symlinks to be resolved first.
+fn names(path: &Path) -> Vec<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some("..".into()),
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => None,
+        })
+        .collect()
+}
+
+/// `path` as an absolute path without `.` and `..`, resolved lexically.
+fn normalize(path: &Path) -> PathBuf {
+    names(path)
+        .into_iter()
+        .fold(PathBuf::from("/"), |mut normalized, name| {
+            if name == ".." {
+                normalized.pop();
+            } else {
+                normalized.push(name);
+            }
+            normalized
+        })
+}
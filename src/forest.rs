@@ -0,0 +1,151 @@
+use crate::{EntryInfo, OrphanPolicy, WalkTree, WalkTreeBuilder, WalkTreeError};
+use bimap::BiMap;
+use indextree::Arena;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Walks several roots into a single `WalkTree`, created by `WalkTree::load_many`.
+///
+/// Every root becomes a root node of the tree. The tree's `root()` is the
+/// closest directory containing all of them and the keys of `WalkTree::map`
+/// are relative to it, so walking `src` and `vendor` yields the keys `src`,
+/// `src/lib.rs`, `vendor` and so on. Roots that are the same directory or
+/// contain one another fail the walk with `WalkTreeError::InvalidRoot`.
+/// `WalkTree::refresh` and `WalkTreeBuilder::watch` take a single root.
+pub struct ForestBuilder<T> {
+    roots: Vec<WalkTreeBuilder<T>>,
+}
+
+impl WalkTree<EntryInfo> {
+    pub fn load_many<I, P>(roots: I) -> ForestBuilder<EntryInfo>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        WalkTreeBuilder::load_many(roots)
+    }
+}
+
+impl WalkTreeBuilder<EntryInfo> {
+    pub fn load_many<I, P>(roots: I) -> ForestBuilder<EntryInfo>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        ForestBuilder {
+            roots: roots
+                .into_iter()
+                .map(|root| WalkTreeBuilder::load(root.as_ref()))
+                .collect(),
+        }
+    }
+}
+
+impl<T> ForestBuilder<T> {
+    /// Configures every root with `f`, for the options they share.
+    pub fn with_each<U, F>(self, f: F) -> ForestBuilder<U>
+    where
+        F: FnMut(WalkTreeBuilder<T>) -> WalkTreeBuilder<U>,
+    {
+        ForestBuilder {
+            roots: self.roots.into_iter().map(f).collect(),
+        }
+    }
+    /// Configures the root loaded from `root` with `f`, on top of the options
+    /// set with `with_each` so far. Does nothing if `root` is not one of the roots.
+    pub fn with_root<F>(mut self, root: &Path, f: F) -> Self
+    where
+        F: FnOnce(WalkTreeBuilder<T>) -> WalkTreeBuilder<T>,
+    {
+        if let Some(index) = self.roots.iter().position(|b| b.root_dir == root) {
+            let builder = self.roots.remove(index);
+            self.roots.insert(index, f(builder));
+        }
+        self
+    }
+    /// Walks every root in turn, each with its own options and error policy.
+    pub fn walk(self) -> Result<WalkTree<T>, WalkTreeError> {
+        let mut roots: Vec<(PathBuf, WalkTreeBuilder<T>, _)> = Vec::with_capacity(self.roots.len());
+        for mut builder in self.roots {
+            let pool = builder.prepare()?;
+            let root = builder.tree_root();
+            let overlap = roots
+                .iter()
+                .find(|(other, ..)| root.starts_with(other) || other.starts_with(&root));
+            if let Some((other, ..)) = overlap {
+                return Err(WalkTreeError::InvalidRoot {
+                    path: builder.root_dir,
+                    reason: format!("overlaps the root {}", other.display()),
+                });
+            }
+            roots.push((root, builder, pool));
+        }
+        let base = common_ancestor(roots.iter().map(|(root, ..)| root.parent().unwrap_or(root)));
+
+        let mut arena = Arena::new();
+        let mut map = BiMap::new();
+        let mut errors = Vec::new();
+        let mut stamps = HashMap::new();
+        let mut dirs = HashSet::new();
+        let mut builders = Vec::with_capacity(roots.len());
+        for (root, mut builder, pool) in roots {
+            let prefix = root.strip_prefix(&base).unwrap_or(&root).to_path_buf();
+            // Joining the root's own empty key would add a trailing separator.
+            let key = |path: PathBuf| {
+                if path.as_os_str().is_empty() {
+                    prefix.clone()
+                } else {
+                    prefix.join(path)
+                }
+            };
+            let mut filter = builder.compose_filter()?;
+            let (nodes, walk_errors, walk_stamps, walk_dirs) =
+                builder.scan(pool.as_ref(), &mut filter)?;
+            for node in nodes {
+                map.insert(key(node.path), arena.new_node(node.data));
+            }
+            errors.extend(walk_errors);
+            stamps.extend(
+                walk_stamps
+                    .into_iter()
+                    .map(|(path, stamp)| (key(path), stamp)),
+            );
+            dirs.extend(walk_dirs.into_iter().map(key));
+            builders.push((prefix, builder));
+        }
+
+        let policies = builders
+            .iter()
+            .map(|(prefix, builder)| (prefix.clone(), builder.orphans))
+            .collect::<Vec<_>>();
+        // Orphans never leave their own root, whose policy applies to them.
+        let orphans = |key: &Path| {
+            policies
+                .iter()
+                .find(|(prefix, _)| key.starts_with(prefix))
+                .map_or(OrphanPolicy::default(), |(_, policy)| *policy)
+        };
+        let mut tree = WalkTree::build(base, arena, map, errors, stamps, dirs, orphans);
+        tree.loaded_many = true;
+        for (prefix, mut builder) in builders {
+            tree = builder.store_cache(tree, &prefix)?;
+        }
+        Ok(tree)
+    }
+}
+
+/// The longest path that all of `paths` start with.
+fn common_ancestor<'a>(mut paths: impl Iterator<Item = &'a Path>) -> PathBuf {
+    let Some(first) = paths.next() else {
+        return PathBuf::new();
+    };
+    paths.fold(first.to_path_buf(), |base, path| {
+        base.components()
+            .zip(path.components())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect()
+    })
+}
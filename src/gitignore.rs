@@ -0,0 +1,207 @@
+use crate::{FileSystem, RealFs, WalkTreeError};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Which ignore files `WalkTreeBuilder::with_ignore` honors.
+///
+/// Precedence follows git: a deeper directory wins over its parents, `.ignore`
+/// wins over `.gitignore` in the same directory, and both win over
+/// `.git/info/exclude` and the global excludes file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IgnoreOptions {
+    /// `.gitignore` files in the walked directories and their parents up to the repository root.
+    pub git_ignore: bool,
+    /// `.ignore` files, with the same semantics as `.gitignore`.
+    pub ignore: bool,
+    /// The file named by git's `core.excludesFile`.
+    pub git_global: bool,
+    /// `.git/info/exclude` of the enclosing repository.
+    pub git_exclude: bool,
+}
+
+impl Default for IgnoreOptions {
+    fn default() -> Self {
+        IgnoreOptions {
+            git_ignore: true,
+            ignore: true,
+            git_global: true,
+            git_exclude: true,
+        }
+    }
+}
+
+/// Ignore files found in one directory, in order of precedence.
+struct DirIgnores {
+    matchers: Vec<Gitignore>,
+    is_repo: bool,
+}
+
+pub(crate) struct IgnoreMatcher {
+    options: IgnoreOptions,
+    root: PathBuf,
+    absolute_root: PathBuf,
+    /// Topmost directory whose ignore files apply: the repository containing
+    /// the root, or the root itself outside of a repository.
+    ceiling: PathBuf,
+    dirs: HashMap<PathBuf, DirIgnores>,
+    global: Option<Gitignore>,
+    errors: Vec<WalkTreeError>,
+    /// Where ignore files are read from, `None` for the disk.
+    fs: Option<Arc<dyn FileSystem>>,
+}
+
+impl IgnoreMatcher {
+    pub(crate) fn new(
+        root: &Path,
+        options: IgnoreOptions,
+        fs: Option<Arc<dyn FileSystem>>,
+    ) -> Self {
+        let disk = fs.as_deref().unwrap_or(&RealFs);
+        let absolute_root = disk
+            .canonicalize(root)
+            .unwrap_or_else(|_| root.to_path_buf());
+        let ceiling = absolute_root
+            .ancestors()
+            .find(|dir| disk.metadata(&dir.join(".git")).is_ok())
+            .unwrap_or(&absolute_root)
+            .to_path_buf();
+        IgnoreMatcher {
+            options,
+            root: root.to_path_buf(),
+            absolute_root,
+            ceiling,
+            dirs: HashMap::new(),
+            global: None,
+            errors: Vec::new(),
+            fs,
+        }
+    }
+
+    pub(crate) fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let path = match path.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() => return false,
+            Ok(relative) => self.absolute_root.join(relative),
+            Err(_) => return false,
+        };
+        for dir in path.ancestors().skip(1) {
+            let ignores = self.dir_ignores(dir);
+            for matcher in &ignores.matchers {
+                match matcher.matched_path_or_any_parents(&path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+            if ignores.is_repo {
+                return self
+                    .global(dir)
+                    .matched_path_or_any_parents(&path, is_dir)
+                    .is_ignore();
+            }
+            if dir == self.ceiling {
+                break;
+            }
+        }
+        false
+    }
+
+    pub(crate) fn take_errors(&mut self) -> Vec<WalkTreeError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    fn dir_ignores(&mut self, dir: &Path) -> &DirIgnores {
+        if !self.dirs.contains_key(dir) {
+            let fs = self.fs.clone();
+            let fs = fs.as_deref().unwrap_or(&RealFs);
+            let is_repo = fs.metadata(&dir.join(".git")).is_ok();
+            let mut files = Vec::new();
+            if self.options.ignore {
+                files.push(dir.join(".ignore"));
+            }
+            if self.options.git_ignore {
+                files.push(dir.join(".gitignore"));
+            }
+            if is_repo && self.options.git_exclude {
+                files.push(dir.join(".git/info/exclude"));
+            }
+            let matchers = files
+                .into_iter()
+                .filter(|file| fs.metadata(file).is_ok_and(|m| m.is_file()))
+                .filter_map(|file| {
+                    let mut builder = GitignoreBuilder::new(dir);
+                    let err = add_file(&mut builder, fs, &file);
+                    self.push_error(&file, err);
+                    let matcher = builder.build();
+                    self.push_error(&file, matcher.as_ref().err().cloned());
+                    matcher.ok()
+                })
+                .collect();
+            let ignores = DirIgnores { matchers, is_repo };
+            self.dirs.insert(dir.to_path_buf(), ignores);
+        }
+        &self.dirs[dir]
+    }
+
+    fn global(&mut self, repo: &Path) -> &Gitignore {
+        if self.global.is_none() {
+            // The global excludes belong to the disk's git configuration.
+            let global = if self.options.git_global && self.fs.is_none() {
+                let (matcher, err) = GitignoreBuilder::new(repo).build_global();
+                self.push_error(repo, err);
+                matcher
+            } else {
+                Gitignore::empty()
+            };
+            self.global = Some(global);
+        }
+        self.global.as_ref().unwrap()
+    }
+
+    fn push_error(&mut self, path: &Path, err: Option<ignore::Error>) {
+        if let Some(source) = err {
+            self.errors.push(WalkTreeError::IgnoreFile {
+                path: path.to_path_buf(),
+                source,
+            });
+        }
+    }
+}
+
+/// Like `GitignoreBuilder::add`, but reads the file from `fs`.
+fn add_file(
+    builder: &mut GitignoreBuilder,
+    fs: &dyn FileSystem,
+    file: &Path,
+) -> Option<ignore::Error> {
+    let tagged = |err| ignore::Error::WithPath {
+        path: file.to_path_buf(),
+        err: Box::new(err),
+    };
+    let contents = match fs.read(file) {
+        Ok(contents) => contents,
+        Err(err) => return Some(tagged(ignore::Error::Io(err))),
+    };
+    let mut errors = String::from_utf8_lossy(&contents)
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let err = builder.add_line(Some(file.to_path_buf()), line).err()?;
+            Some(tagged(ignore::Error::WithLineNumber {
+                line: index as u64 + 1,
+                err: Box::new(err),
+            }))
+        })
+        .collect::<Vec<_>>();
+    match errors.len() {
+        0 => None,
+        1 => errors.pop(),
+        _ => Some(ignore::Error::Partial(errors)),
+    }
+}
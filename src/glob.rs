@@ -0,0 +1,102 @@
+use crate::WalkTreeError;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+
+/// Include and exclude patterns of a `WalkTreeBuilder`, matched against
+/// paths relative to the root directory.
+///
+/// Within each list the last matching pattern wins, a leading `!` negates a
+/// pattern and a trailing `/` restricts it to directories.
+pub(crate) struct GlobFilter {
+    root: PathBuf,
+    include: Patterns,
+    exclude: Patterns,
+}
+
+struct Patterns {
+    set: GlobSet,
+    patterns: Vec<Pattern>,
+}
+
+struct Pattern {
+    negated: bool,
+    dir_only: bool,
+}
+
+impl GlobFilter {
+    pub(crate) fn new(
+        root: &Path,
+        include: &[String],
+        exclude: &[String],
+    ) -> Result<Self, WalkTreeError> {
+        Ok(GlobFilter {
+            root: root.to_path_buf(),
+            include: Patterns::new(include)?,
+            exclude: Patterns::new(exclude)?,
+        })
+    }
+
+    /// Excluded directories are pruned with everything below them. Include
+    /// patterns only select files, either directly or through a matching
+    /// parent directory, so that every directory stays reachable.
+    pub(crate) fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => return true,
+        };
+        if self.exclude.last_match(relative, is_dir) == Some(true) {
+            return false;
+        }
+        if is_dir || self.include.patterns.is_empty() {
+            return true;
+        }
+        relative
+            .ancestors()
+            .take_while(|a| !a.as_os_str().is_empty())
+            .find_map(|a| self.include.last_match(a, a != relative))
+            .unwrap_or(false)
+    }
+}
+
+impl Patterns {
+    fn new(globs: &[String]) -> Result<Self, WalkTreeError> {
+        let mut set = GlobSetBuilder::new();
+        let mut patterns = Vec::with_capacity(globs.len());
+        for glob in globs {
+            let (negated, pattern) = match glob.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, glob.as_str()),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            let compiled = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|source| WalkTreeError::InvalidGlob {
+                    pattern: glob.clone(),
+                    source,
+                })?;
+            set.add(compiled);
+            patterns.push(Pattern { negated, dir_only });
+        }
+        let set = set.build().map_err(|source| WalkTreeError::InvalidGlob {
+            pattern: globs.join(", "),
+            source,
+        })?;
+        Ok(Patterns { set, patterns })
+    }
+
+    /// `Some(true)` if the last pattern matching `path` selects it, `Some(false)`
+    /// if it is a negation, `None` if no pattern applies.
+    fn last_match(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        self.set
+            .matches(path)
+            .into_iter()
+            .rev()
+            .map(|i| &self.patterns[i])
+            .find(|pattern| is_dir || !pattern.dir_only)
+            .map(|pattern| !pattern.negated)
+    }
+}
@@ -1,39 +1,372 @@
 use bimap::BiMap;
-use indextree::{Arena, NodeId};
+use cache::ScanCache;
+use entry::Stamp;
+use gitignore::IgnoreMatcher;
+use glob::GlobFilter;
+use indextree::{Arena, NodeEdge, NodeId};
+use parallel::{ParallelWalk, Sorter};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 use std::{
+    cell::RefCell,
     cmp::Ordering,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt, io,
     path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
 };
-use walkdir::{DirEntry, WalkDir};
+use walkdir::WalkDir;
+
+mod cache;
+mod diff;
+mod entry;
+mod filesystem;
+mod forest;
+mod gitignore;
+mod glob;
+mod parallel;
+mod refresh;
+mod render;
+mod root;
+#[cfg(feature = "serde")]
+mod serialize;
+mod subtree;
+mod traverse;
+#[cfg(target_os = "linux")]
+mod watch;
+
+pub use diff::TreeDiff;
+pub use entry::{DirEntry, EntryInfo, FileKind};
+pub use filesystem::{FileSystem, MemoryFs, Metadata, RealFs};
+pub use forest::ForestBuilder;
+pub use gitignore::IgnoreOptions;
+pub use refresh::ChangeSummary;
+pub use render::{Charset, Render};
+#[cfg(feature = "serde")]
+pub use serialize::{compact, flat, nested};
+pub use traverse::Visit;
+#[cfg(target_os = "linux")]
+pub use watch::{ChangeEvent, Watcher};
 
 #[derive(Debug)]
-pub enum WalkTreeError {}
+pub enum WalkTreeError {
+    /// An entry could not be read. `path` is `None` when walkdir could not
+    /// attribute the failure to a particular path.
+    Io {
+        path: Option<PathBuf>,
+        depth: usize,
+        source: io::Error,
+    },
+    /// Following symbolic links led back to `ancestor`.
+    SymlinkLoop {
+        ancestor: PathBuf,
+        child: PathBuf,
+        depth: usize,
+    },
+    /// The builder was configured with options that cannot be used together.
+    InvalidOptions(String),
+    /// The mapping function failed for `path`.
+    Map {
+        path: PathBuf,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// An ignore file could not be read or contains invalid patterns.
+    IgnoreFile {
+        path: PathBuf,
+        source: ignore::Error,
+    },
+    /// A pattern passed to `WalkTreeBuilder::include` or `exclude` is not a valid glob.
+    InvalidGlob {
+        pattern: String,
+        source: globset::Error,
+    },
+    /// A serialized tree could not be written or read back.
+    Encoding(Box<dyn Error + Send + Sync>),
+    /// The root given to `WalkTreeBuilder::load` could not be resolved, see
+    /// `WalkTreeBuilder::with_root_resolution`, or is not the root of the
+    /// tree it should refresh.
+    InvalidRoot { path: PathBuf, reason: String },
+    /// Two walk options cannot be used together; an option given twice conflicts with itself.
+    ConflictingOptions {
+        first: &'static str,
+        second: &'static str,
+    },
+    /// `WalkDirOption::MinDepth` is greater than `WalkDirOption::MaxDepth`, so nothing would be walked.
+    DepthRange { min_depth: usize, max_depth: usize },
+}
 
-#[derive(Debug, PartialEq, Eq)]
+impl WalkTreeError {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            WalkTreeError::Io { path, .. } => path.as_deref(),
+            WalkTreeError::SymlinkLoop { child, .. } => Some(child),
+            WalkTreeError::InvalidOptions(_)
+            | WalkTreeError::InvalidGlob { .. }
+            | WalkTreeError::Encoding(_)
+            | WalkTreeError::ConflictingOptions { .. }
+            | WalkTreeError::DepthRange { .. } => None,
+            WalkTreeError::Map { path, .. } => Some(path),
+            WalkTreeError::IgnoreFile { path, .. } => Some(path),
+            WalkTreeError::InvalidRoot { path, .. } => Some(path),
+        }
+    }
+}
+
+impl fmt::Display for WalkTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalkTreeError::Io {
+                path: Some(path),
+                source,
+                ..
+            } => write!(f, "IO error for {}: {}", path.display(), source),
+            WalkTreeError::Io { source, .. } => write!(f, "IO error: {}", source),
+            WalkTreeError::SymlinkLoop {
+                ancestor, child, ..
+            } => write!(
+                f,
+                "symlink loop: {} points to ancestor {}",
+                child.display(),
+                ancestor.display()
+            ),
+            WalkTreeError::InvalidOptions(msg) => write!(f, "invalid options: {}", msg),
+            WalkTreeError::Map { path, source } => {
+                write!(f, "failed to map {}: {}", path.display(), source)
+            }
+            WalkTreeError::IgnoreFile { path, source } => {
+                write!(f, "invalid ignore file {}: {}", path.display(), source)
+            }
+            WalkTreeError::InvalidGlob { pattern, source } => {
+                write!(f, "invalid glob {:?}: {}", pattern, source)
+            }
+            WalkTreeError::Encoding(source) => write!(f, "invalid serialized tree: {}", source),
+            WalkTreeError::InvalidRoot { path, reason } => {
+                write!(f, "invalid root {}: {}", path.display(), reason)
+            }
+            WalkTreeError::ConflictingOptions { first, second } if first == second => {
+                write!(f, "invalid options: {} is set more than once", first)
+            }
+            WalkTreeError::ConflictingOptions { first, second } => {
+                write!(f, "invalid options: {} conflicts with {}", first, second)
+            }
+            WalkTreeError::DepthRange {
+                min_depth,
+                max_depth,
+            } => write!(
+                f,
+                "invalid options: MinDepth({}) is greater than MaxDepth({})",
+                min_depth, max_depth
+            ),
+        }
+    }
+}
+
+impl Error for WalkTreeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalkTreeError::Io { source, .. } => Some(source),
+            WalkTreeError::Map { source, .. } => Some(source.as_ref()),
+            WalkTreeError::IgnoreFile { source, .. } => Some(source),
+            WalkTreeError::InvalidGlob { source, .. } => Some(source),
+            WalkTreeError::Encoding(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<walkdir::Error> for WalkTreeError {
+    fn from(err: walkdir::Error) -> Self {
+        let depth = err.depth();
+        if let Some(ancestor) = err.loop_ancestor() {
+            return WalkTreeError::SymlinkLoop {
+                ancestor: ancestor.to_path_buf(),
+                child: err.path().map(Path::to_path_buf).unwrap_or_default(),
+                depth,
+            };
+        }
+        let path = err.path().map(Path::to_path_buf);
+        WalkTreeError::Io {
+            path,
+            depth,
+            source: err.into(),
+        }
+    }
+}
+
+/// What `WalkTreeBuilder::walk` does with errors hit along the way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorPolicy {
+    /// Stop at the first error and return it.
+    FailFast,
+    /// Keep walking and record every error in `WalkTree::errors`.
+    #[default]
+    Collect,
+    /// Keep walking and discard errors.
+    Ignore,
+}
+
+/// What happens to the tree when a `WalkTreeBuilder::with_try_map` mapper fails.
+/// The error itself is reported according to the `ErrorPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MapErrorAction {
+    /// Leave the failed entry out of the tree.
+    #[default]
+    SkipNode,
+    /// Leave the failed entry and everything below it out of the tree.
+    SkipSubtree,
+    /// Stop the walk and return the error.
+    Abort,
+}
+
+/// Where nodes go when their parent directory is not in the tree, because
+/// `WalkDirOption::MinDepth` left it out, a filter rejected it without its
+/// children or its mapper failed with `MapErrorAction::SkipNode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    /// Attach them to their nearest ancestor that is in the tree.
+    Reparent,
+    /// Put placeholders in the tree for the missing directories, mapped like
+    /// walked entries. Where a placeholder cannot be mapped, orphans are
+    /// attached to their nearest ancestor instead.
+    Placeholder,
+    /// Leave them as roots of their own, see `WalkTree::roots`.
+    #[default]
+    Forest,
+}
+
+impl ErrorPolicy {
+    fn handle(
+        self,
+        err: WalkTreeError,
+        errors: &mut Vec<WalkTreeError>,
+    ) -> Result<(), WalkTreeError> {
+        match self {
+            ErrorPolicy::FailFast => return Err(err),
+            ErrorPolicy::Collect => errors.push(err),
+            ErrorPolicy::Ignore => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
 pub struct WalkTree<T> {
     pub arena: Arena<T>,
+    /// Paths relative to `root()`, the root itself being the empty path.
     pub map: BiMap<PathBuf, NodeId>,
+    pub errors: Vec<WalkTreeError>,
+    root: PathBuf,
+    stamps: HashMap<PathBuf, Stamp>,
+    /// Keys of the entries that were directories when walked.
+    dirs: HashSet<PathBuf>,
+    /// Whether the tree was loaded from several roots, which no single
+    /// builder can refresh.
+    loaded_many: bool,
 }
 
-impl<T> WalkTree<T> {
-    pub fn load(path: &Path) -> WalkTreeBuilder<T> {
+impl<T: PartialEq> PartialEq for WalkTree<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root && self.arena == other.arena && self.map == other.map
+    }
+}
+
+impl<T: Eq> Eq for WalkTree<T> {}
+
+impl WalkTree<EntryInfo> {
+    pub fn load(path: &Path) -> WalkTreeBuilder<EntryInfo> {
         WalkTreeBuilder::load(path)
     }
-    fn build(mut arena: Arena<T>, map: BiMap<PathBuf, NodeId>) -> Self {
-        for (path, node_id) in map.iter() {
-            if let Some(parent) = path.parent() {
-                if let Some(t) = map.get_by_left(&parent.to_path_buf()) {
-                    t.append(*node_id, &mut arena);
-                }
+}
+
+impl<T> WalkTree<T> {
+    /// Links every node below its parent, or as `orphans` says for the key when its parent is missing.
+    fn build<F>(
+        root: PathBuf,
+        arena: Arena<T>,
+        map: BiMap<PathBuf, NodeId>,
+        errors: Vec<WalkTreeError>,
+        stamps: HashMap<PathBuf, Stamp>,
+        dirs: HashSet<PathBuf>,
+        orphans: F,
+    ) -> Self
+    where
+        F: Fn(&Path) -> OrphanPolicy,
+    {
+        let mut tree = Self {
+            arena,
+            map,
+            errors,
+            root,
+            stamps,
+            dirs,
+            loaded_many: false,
+        };
+        // Link in arena order so siblings keep the order they were walked in.
+        let mut nodes = tree
+            .map
+            .iter()
+            .map(|(path, node_id)| (path.clone(), *node_id))
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|(_, node_id)| *node_id);
+        for (path, node_id) in nodes {
+            tree.attach(&path, node_id, orphans(&path));
+        }
+        tree
+    }
+    /// Appends `node_id` to the node of its parent directory or, unless
+    /// `orphans` is `OrphanPolicy::Forest`, of its nearest ancestor in the tree.
+    pub(crate) fn attach(&mut self, key: &Path, node_id: NodeId, orphans: OrphanPolicy) {
+        let mut ancestors = key.ancestors().skip(1);
+        let parent = match orphans {
+            OrphanPolicy::Forest => ancestors.next().and_then(|p| self.map.get_by_left(p)),
+            OrphanPolicy::Reparent | OrphanPolicy::Placeholder => {
+                ancestors.find_map(|p| self.map.get_by_left(p))
             }
+        };
+        if let Some(parent) = parent.copied() {
+            parent.append(node_id, &mut self.arena);
         }
-        Self { arena, map }
     }
+    /// The walked directory, canonicalized if it existed at the time of the walk.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    /// The nodes without a parent, in the order they were walked. A tree has
+    /// more than one when it was loaded from several roots or keeps orphans
+    /// as a forest, and none when it is empty.
+    pub fn roots(&self) -> Vec<NodeId> {
+        self.node_ids()
+            .into_iter()
+            .filter(|id| self.arena[*id].parent().is_none())
+            .collect()
+    }
+    /// The path of `node_id` relative to `root()`.
     pub fn get_path_by_node_id(&self, node_id: NodeId) -> Option<&PathBuf> {
         self.map.get_by_right(&node_id)
     }
+    /// Looks up a relative `path` as a key, relative to `root()`, and an
+    /// absolute one as a path inside of `root()`.
     pub fn get_node_id_by_path(&self, path: &Path) -> Option<&NodeId> {
-        self.map.get_by_left(path)
+        if path.is_relative() {
+            return self.map.get_by_left(path);
+        }
+        let lookup = |absolute: &Path| {
+            let key = absolute.strip_prefix(&self.root).ok()?;
+            self.map.get_by_left(key)
+        };
+        if let Some(node_id) = lookup(path) {
+            return Some(node_id);
+        }
+        // The path may reach the root through a symlink. The last component
+        // is resolved last, since a symlink in the tree is a node of its own.
+        let parent = path.parent().and_then(|p| p.canonicalize().ok());
+        if let (Some(parent), Some(name)) = (parent, path.file_name()) {
+            if let Some(node_id) = lookup(&parent.join(name)) {
+                return Some(node_id);
+            }
+        }
+        lookup(&path.canonicalize().ok()?)
     }
     pub fn get_item_by_path(&self, path: &Path) -> Option<&T> {
         if let Some(node_id) = self.get_node_id_by_path(path) {
@@ -43,31 +376,161 @@ impl<T> WalkTree<T> {
     }
     pub fn get_item_by_node_id(&self, node_id: NodeId) -> Option<&T> {
         if let Some(node) = self.arena.get(node_id) {
-            return Some(&node.get());
+            return Some(node.get());
+        }
+        None
+    }
+    pub fn get_item_mut_by_path(&mut self, path: &Path) -> Option<&mut T> {
+        if let Some(node_id) = self.get_node_id_by_path(path) {
+            return self.get_item_mut_by_node_id(*node_id);
+        }
+        None
+    }
+    pub fn get_item_mut_by_node_id(&mut self, node_id: NodeId) -> Option<&mut T> {
+        if let Some(node) = self.arena.get_mut(node_id) {
+            return Some(node.get_mut());
         }
         None
     }
-    // pub fn get_mut_item_by_path(&mut self, path: &Path) -> Option<&T> {
-    //     if let Some(node_id) = self.get_node_id_by_path(path) {
-    //         if let Some(node) = self.arena.get_mut(*node_id) {
-    //             return Some(&node.get_mut());
-    //         }
-    //     }
-    //     None
-    // }
-    // pub fn get_mut_item_by_node_id(&mut self, node_id: NodeId) -> Option<&T> {
-    //     if let Some(node) = self.arena.get_mut(node_id) {
-    //         return Some(&node.get_mut());
-    //     }
-    //     None
-    // }
+    /// Calls `f` on every node, in the order the entries were walked.
+    pub fn for_each_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Path, &mut T),
+    {
+        for node_id in self.node_ids() {
+            if let Some(path) = self.map.get_by_right(&node_id) {
+                f(path, self.arena[node_id].get_mut());
+            }
+        }
+    }
+    /// Replaces every node with the value returned by `f`, in the order the entries were walked.
+    pub fn map_in_place<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Path, &T) -> T,
+    {
+        self.for_each_mut(|path, data| *data = f(path, data));
+    }
+    /// Folds the tree bottom-up: `f` receives each node together with the
+    /// results already computed for its children, and the results form a new
+    /// tree of the same shape.
+    pub fn aggregate<R, F>(&self, mut f: F) -> WalkTree<R>
+    where
+        F: FnMut(&T, &[&R]) -> R,
+    {
+        let node_ids = self.node_ids();
+        let mut results = HashMap::with_capacity(node_ids.len());
+        for root in self.roots() {
+            for edge in root.traverse(&self.arena) {
+                if let NodeEdge::End(node_id) = edge {
+                    let children = node_id
+                        .children(&self.arena)
+                        .map(|child| &results[&child])
+                        .collect::<Vec<_>>();
+                    let result = f(self.arena[node_id].get(), &children);
+                    results.insert(node_id, result);
+                }
+            }
+        }
+        self.with_data(node_ids, results)
+    }
+    /// Copies the shape of the tree onto `data`, which must hold a value for
+    /// every node in `node_ids`. Keys of other nodes are left out.
+    fn with_data<R>(&self, node_ids: Vec<NodeId>, mut data: HashMap<NodeId, R>) -> WalkTree<R> {
+        let mut arena = Arena::new();
+        let new_ids = node_ids
+            .iter()
+            .map(|id| (*id, arena.new_node(data.remove(id).unwrap())))
+            .collect::<HashMap<_, _>>();
+        for node_id in &node_ids {
+            for child in node_id.children(&self.arena) {
+                new_ids[node_id].append(new_ids[&child], &mut arena);
+            }
+        }
+        let map = self
+            .map
+            .iter()
+            .filter_map(|(path, id)| Some((path.clone(), *new_ids.get(id)?)))
+            .collect();
+        WalkTree {
+            arena,
+            map,
+            errors: Vec::new(),
+            root: self.root.clone(),
+            stamps: self.stamps.clone(),
+            dirs: self.dirs.clone(),
+            loaded_many: self.loaded_many,
+        }
+    }
+    /// Re-sorts the children of every node with `cmp`, which receives the
+    /// keys and items of two siblings. The sort is stable, and roots keep
+    /// their order.
+    pub fn sort_children_by<F>(&mut self, mut cmp: F)
+    where
+        F: FnMut((&Path, &T), (&Path, &T)) -> Ordering,
+    {
+        for node_id in self.node_ids() {
+            self.sort_children(node_id, &mut cmp);
+        }
+    }
+    pub(crate) fn sort_children<F>(&mut self, parent: NodeId, mut cmp: F)
+    where
+        F: FnMut((&Path, &T), (&Path, &T)) -> Ordering,
+    {
+        let mut children = parent.children(&self.arena).collect::<Vec<_>>();
+        let node = |id: &NodeId| {
+            (
+                self.map.get_by_right(id).unwrap().as_path(),
+                self.arena[*id].get(),
+            )
+        };
+        children.sort_by(|a, b| cmp(node(a), node(b)));
+        for child in children {
+            child.detach(&mut self.arena);
+            parent.append(child, &mut self.arena);
+        }
+    }
+    fn node_ids(&self) -> Vec<NodeId> {
+        self.arena
+            .iter()
+            .filter(|node| !node.is_removed())
+            .filter_map(|node| self.arena.get_node_id(node))
+            .collect()
+    }
+}
+
+type FilterFn = Box<dyn FnMut(&DirEntry) -> bool>;
+type MapResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+type MapFn<T> = Box<dyn FnMut(DirEntry) -> MapResult<T>>;
+type ParMapFn<T> = Box<dyn FnMut(Vec<DirEntry>, &ThreadPool) -> Vec<Mapped<T>>>;
+type SortFn = Box<dyn FnMut(&DirEntry, &DirEntry) -> Ordering + Send + Sync>;
+
+enum Mapper<T> {
+    Serial(MapFn<T>),
+    Parallel(ParMapFn<T>),
+}
+
+struct Mapped<T> {
+    path: PathBuf,
+    is_dir: bool,
+    result: MapResult<T>,
 }
 
 pub struct WalkTreeBuilder<T> {
     root_dir: PathBuf,
-    fn_filter: Option<fn(&walkdir::DirEntry) -> bool>,
-    fn_map: Option<fn(walkdir::DirEntry) -> T>,
+    fn_filter: Option<FilterFn>,
+    fn_map: Mapper<T>,
     walkdir_modes: WalkDirModes,
+    error_policy: ErrorPolicy,
+    map_error_action: MapErrorAction,
+    threads: Option<usize>,
+    ignore: Option<IgnoreOptions>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    cache: Option<Box<dyn ScanCache<T>>>,
+    resolve_root: bool,
+    stamps: bool,
+    fs: Option<Arc<dyn FileSystem>>,
+    orphans: OrphanPolicy,
 }
 
 struct WalkDirModes(Vec<WalkDirOption>);
@@ -79,9 +542,81 @@ pub enum WalkDirOption {
     MaxOpen(usize),
     MinDepth(usize),
     SameFileSystem,
-    SortBy(fn(&DirEntry, &DirEntry) -> Ordering),
+    SortBy(SortFn),
     SortByFileName,
-    SortByKey(fn(&DirEntry) -> Ordering),
+    /// Comparator derived from a key function, see `WalkDirOption::sort_by_key`.
+    SortByKey(SortFn),
+}
+
+impl WalkDirOption {
+    /// The name of the variant, as used in `WalkTreeError::ConflictingOptions`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WalkDirOption::ContentsFirst => "ContentsFirst",
+            WalkDirOption::FollowLinks => "FollowLinks",
+            WalkDirOption::MaxDepth(_) => "MaxDepth",
+            WalkDirOption::MaxOpen(_) => "MaxOpen",
+            WalkDirOption::MinDepth(_) => "MinDepth",
+            WalkDirOption::SameFileSystem => "SameFileSystem",
+            WalkDirOption::SortBy(_) => "SortBy",
+            WalkDirOption::SortByFileName => "SortByFileName",
+            WalkDirOption::SortByKey(_) => "SortByKey",
+        }
+    }
+    pub fn sort_by<F>(f: F) -> Self
+    where
+        F: FnMut(&DirEntry, &DirEntry) -> Ordering + Send + Sync + 'static,
+    {
+        WalkDirOption::SortBy(Box::new(f))
+    }
+    pub fn sort_by_key<K, F>(mut f: F) -> Self
+    where
+        K: Ord,
+        F: FnMut(&DirEntry) -> K + Send + Sync + 'static,
+    {
+        WalkDirOption::SortByKey(Box::new(move |a, b| f(a).cmp(&f(b))))
+    }
+}
+
+/// The walk options of a builder once validated, see `WalkTreeBuilder::walk_config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WalkConfig {
+    pub contents_first: bool,
+    pub follow_links: bool,
+    /// `usize::MAX` unless limited with `WalkDirOption::MaxDepth`.
+    pub max_depth: usize,
+    /// Directories kept open at once by the sequential walk, `10` by default like walkdir.
+    pub max_open: usize,
+    pub min_depth: usize,
+    pub same_file_system: bool,
+    pub sort: Option<SortOrder>,
+    pub orphans: OrphanPolicy,
+}
+
+/// How the children of a directory are ordered during the walk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// `WalkDirOption::SortByFileName`.
+    FileName,
+    /// `WalkDirOption::SortBy`.
+    Comparator,
+    /// `WalkDirOption::SortByKey`.
+    Key,
+}
+
+impl Default for WalkConfig {
+    fn default() -> Self {
+        WalkConfig {
+            contents_first: false,
+            follow_links: false,
+            max_depth: usize::MAX,
+            max_open: 10,
+            min_depth: 0,
+            same_file_system: false,
+            sort: None,
+            orphans: OrphanPolicy::default(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -90,26 +625,96 @@ struct WalkTreeNode<T> {
     pub data: T,
 }
 
+/// The nodes, errors, stamps and directories of a walk before they are
+/// linked into a tree.
+type Scan<T> = (
+    Vec<WalkTreeNode<T>>,
+    Vec<WalkTreeError>,
+    HashMap<PathBuf, Stamp>,
+    HashSet<PathBuf>,
+);
+
 impl WalkDirModes {
     fn new() -> Self {
         Self(Vec::<WalkDirOption>::new())
     }
-    fn check_compability(&self) -> Result<(), WalkTreeError> {
-        Ok(())
+    /// Rejects duplicated and competing options and an empty depth range, and
+    /// returns the resulting configuration.
+    fn check_compability(&self) -> Result<WalkConfig, WalkTreeError> {
+        let mut config = WalkConfig::default();
+        let mut seen = Vec::<&WalkDirOption>::with_capacity(self.0.len());
+        let is_sort = |o: &WalkDirOption| {
+            matches!(
+                o,
+                WalkDirOption::SortBy(_)
+                    | WalkDirOption::SortByFileName
+                    | WalkDirOption::SortByKey(_)
+            )
+        };
+        for option in &self.0 {
+            let conflict = seen.iter().find(|earlier| {
+                earlier.name() == option.name() || (is_sort(earlier) && is_sort(option))
+            });
+            if let Some(earlier) = conflict {
+                return Err(WalkTreeError::ConflictingOptions {
+                    first: earlier.name(),
+                    second: option.name(),
+                });
+            }
+            seen.push(option);
+            match option {
+                WalkDirOption::ContentsFirst => config.contents_first = true,
+                WalkDirOption::FollowLinks => config.follow_links = true,
+                WalkDirOption::MaxDepth(i) => config.max_depth = *i,
+                WalkDirOption::MaxOpen(i) => config.max_open = *i,
+                WalkDirOption::MinDepth(i) => config.min_depth = *i,
+                WalkDirOption::SameFileSystem => config.same_file_system = true,
+                WalkDirOption::SortBy(_) => config.sort = Some(SortOrder::Comparator),
+                WalkDirOption::SortByFileName => config.sort = Some(SortOrder::FileName),
+                WalkDirOption::SortByKey(_) => config.sort = Some(SortOrder::Key),
+            }
+        }
+        if config.min_depth > config.max_depth {
+            return Err(WalkTreeError::DepthRange {
+                min_depth: config.min_depth,
+                max_depth: config.max_depth,
+            });
+        }
+        Ok(config)
     }
-    fn compile_walkdir(&self, root_dir: &Path) -> WalkDir {
+    fn compile_walkdir(self, root_dir: &Path) -> WalkDir {
         self.0
-            .iter()
+            .into_iter()
             .fold(WalkDir::new(root_dir), |walkdir, option| match option {
                 WalkDirOption::ContentsFirst => walkdir.contents_first(true),
                 WalkDirOption::FollowLinks => walkdir.follow_links(true),
-                WalkDirOption::MaxDepth(i) => walkdir.max_depth(*i),
-                WalkDirOption::MaxOpen(i) => walkdir.max_open(*i),
-                WalkDirOption::MinDepth(i) => walkdir.min_depth(*i),
+                WalkDirOption::MaxDepth(i) => walkdir.max_depth(i),
+                WalkDirOption::MaxOpen(i) => walkdir.max_open(i),
+                WalkDirOption::MinDepth(i) => walkdir.min_depth(i),
                 WalkDirOption::SameFileSystem => walkdir.same_file_system(true),
-                WalkDirOption::SortBy(f) => walkdir.sort_by(*f),
+                WalkDirOption::SortBy(mut f) | WalkDirOption::SortByKey(mut f) => {
+                    walkdir.sort_by(move |a, b| f(&a.into(), &b.into()))
+                }
                 WalkDirOption::SortByFileName => walkdir.sort_by_file_name(),
-                WalkDirOption::SortByKey(f) => walkdir.sort_by_key(*f),
+            })
+    }
+    fn compile_parallel(self, root_dir: &Path, fs: Option<Arc<dyn FileSystem>>) -> ParallelWalk {
+        self.0
+            .into_iter()
+            .fold(ParallelWalk::new(root_dir, fs), |mut walk, option| {
+                match option {
+                    WalkDirOption::ContentsFirst => walk.contents_first = true,
+                    WalkDirOption::FollowLinks => walk.follow_links = true,
+                    WalkDirOption::MaxDepth(i) => walk.max_depth = i,
+                    WalkDirOption::MaxOpen(_) => {}
+                    WalkDirOption::MinDepth(i) => walk.min_depth = i,
+                    WalkDirOption::SameFileSystem => walk.same_file_system = true,
+                    WalkDirOption::SortBy(f) | WalkDirOption::SortByKey(f) => {
+                        walk.sorter = Some(Sorter::By(f))
+                    }
+                    WalkDirOption::SortByFileName => walk.sorter = Some(Sorter::FileName),
+                }
+                walk
             })
     }
 }
@@ -120,24 +725,95 @@ impl Default for WalkDirModes {
     }
 }
 
-impl<T> WalkTreeBuilder<T> {
-    pub fn load(p: &Path) -> WalkTreeBuilder<T> {
+impl WalkTreeBuilder<EntryInfo> {
+    pub fn load(p: &Path) -> WalkTreeBuilder<EntryInfo> {
         WalkTreeBuilder {
             root_dir: p.to_path_buf(),
             fn_filter: None,
-            fn_map: None,
+            fn_map: Mapper::Serial(Box::new(|e| Ok(EntryInfo::from(e)))),
             walkdir_modes: WalkDirModes::new(),
+            error_policy: ErrorPolicy::default(),
+            map_error_action: MapErrorAction::default(),
+            threads: None,
+            ignore: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            cache: None,
+            resolve_root: false,
+            stamps: false,
+            fs: None,
+            orphans: OrphanPolicy::default(),
         }
     }
-    pub fn with_map(self, f: fn(walkdir::DirEntry) -> T) -> Self {
+}
+
+impl<T> WalkTreeBuilder<T> {
+    pub fn with_map<U, F>(self, mut f: F) -> WalkTreeBuilder<U>
+    where
+        F: FnMut(DirEntry) -> U + 'static,
+    {
+        self.with_try_map(move |e| Ok::<_, Box<dyn Error + Send + Sync>>(f(e)))
+    }
+    pub fn with_try_map<U, E, F>(self, mut f: F) -> WalkTreeBuilder<U>
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+        F: FnMut(DirEntry) -> Result<U, E> + 'static,
+    {
+        self.with_mapper(Mapper::Serial(Box::new(move |e| f(e).map_err(Into::into))))
+    }
+    /// Like `with_map`, but entries are mapped concurrently on the walk's thread pool.
+    pub fn with_par_map<U, F>(self, f: F) -> WalkTreeBuilder<U>
+    where
+        U: Send + 'static,
+        F: Fn(DirEntry) -> U + Send + Sync + 'static,
+    {
+        self.with_par_try_map(move |e| Ok::<_, Box<dyn Error + Send + Sync>>(f(e)))
+    }
+    /// Like `with_try_map`, but entries are mapped concurrently on the walk's thread pool.
+    pub fn with_par_try_map<U, E, F>(self, f: F) -> WalkTreeBuilder<U>
+    where
+        U: Send + 'static,
+        E: Into<Box<dyn Error + Send + Sync>>,
+        F: Fn(DirEntry) -> Result<U, E> + Send + Sync + 'static,
+    {
+        self.with_mapper(Mapper::Parallel(Box::new(move |entries, pool| {
+            pool.install(|| {
+                entries
+                    .into_par_iter()
+                    .map(|e| Mapped {
+                        path: e.path().to_path_buf(),
+                        is_dir: e.file_type().is_dir(),
+                        result: f(e).map_err(Into::into),
+                    })
+                    .collect()
+            })
+        })))
+    }
+    fn with_mapper<U>(self, fn_map: Mapper<U>) -> WalkTreeBuilder<U> {
         WalkTreeBuilder {
-            fn_map: Some(f),
-            ..self
+            root_dir: self.root_dir,
+            fn_filter: self.fn_filter,
+            fn_map,
+            walkdir_modes: self.walkdir_modes,
+            error_policy: self.error_policy,
+            map_error_action: self.map_error_action,
+            threads: self.threads,
+            ignore: self.ignore,
+            include: self.include,
+            exclude: self.exclude,
+            cache: None,
+            resolve_root: self.resolve_root,
+            stamps: self.stamps,
+            fs: self.fs,
+            orphans: self.orphans,
         }
     }
-    pub fn with_fliter(self, f: fn(&walkdir::DirEntry) -> bool) -> Self {
+    pub fn with_fliter<F>(self, f: F) -> Self
+    where
+        F: FnMut(&DirEntry) -> bool + 'static,
+    {
         WalkTreeBuilder {
-            fn_filter: Some(f),
+            fn_filter: Some(Box::new(f)),
             ..self
         }
     }
@@ -145,64 +821,1850 @@ impl<T> WalkTreeBuilder<T> {
         self.walkdir_modes.0.push(mode);
         self
     }
-    pub fn walk(self) -> Result<WalkTree<T>, WalkTreeError> {
-        self.walkdir_modes.check_compability()?;
-        let walkdir = self.walkdir_modes.compile_walkdir(&self.root_dir);
-        let entries = self.apply_fiter_fn(walkdir);
-        let entries = self.apply_map_fn(entries);
-
+    pub fn with_error_policy(self, policy: ErrorPolicy) -> Self {
+        WalkTreeBuilder {
+            error_policy: policy,
+            ..self
+        }
+    }
+    pub fn on_map_error(self, action: MapErrorAction) -> Self {
+        WalkTreeBuilder {
+            map_error_action: action,
+            ..self
+        }
+    }
+    /// Reads directories on a pool of `threads` threads instead of a single
+    /// `walkdir::WalkDir`; `0` uses one thread per CPU. The resulting tree is
+    /// the same as with the sequential walk, but filters see the entries in
+    /// the order their directories were read.
+    pub fn with_threads(self, threads: usize) -> Self {
+        WalkTreeBuilder {
+            threads: Some(threads),
+            ..self
+        }
+    }
+    /// Skips entries excluded by `.gitignore`, `.ignore` and git's exclude files.
+    pub fn with_ignore(self, options: IgnoreOptions) -> Self {
+        WalkTreeBuilder {
+            ignore: Some(options),
+            ..self
+        }
+    }
+    /// Keeps only files matching `globs`, see `exclude` for the syntax.
+    /// Directories are always kept unless excluded.
+    pub fn include<I, S>(mut self, globs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.include
+            .extend(globs.into_iter().map(|g| g.as_ref().to_string()));
+        self
+    }
+    /// Skips entries matching `globs`, relative to the root directory. Globs
+    /// support `**`, `[...]` classes and `{a,b}` alternatives; a leading `!`
+    /// re-includes what an earlier pattern excluded and a trailing `/` only
+    /// matches directories, pruning their whole subtree.
+    pub fn exclude<I, S>(mut self, globs: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.exclude
+            .extend(globs.into_iter().map(|g| g.as_ref().to_string()));
+        self
+    }
+    /// Expands `~`, `~user`, `$VAR` and `${VAR}` in the root and canonicalizes
+    /// it before walking. A root that cannot be resolved, does not exist or is
+    /// not a directory fails the walk with `WalkTreeError::InvalidRoot`,
+    /// whatever the error policy.
+    pub fn with_root_resolution(self) -> Self {
+        WalkTreeBuilder {
+            resolve_root: true,
+            ..self
+        }
+    }
+    /// Records the size, modification time and inode of every entry, which
+    /// lets `WalkTree::refresh` map again only the entries that changed.
+    /// Costs a metadata call per entry; a cache records them either way.
+    pub fn with_stamps(self) -> Self {
+        WalkTreeBuilder {
+            stamps: true,
+            ..self
+        }
+    }
+    /// Reads directories and metadata from `fs` instead of the disk, for
+    /// example a `MemoryFs` in tests. Mappers then see the metadata `fs`
+    /// reports, and global git excludes are not read.
+    pub fn with_file_system<F: FileSystem + 'static>(self, fs: F) -> Self {
+        WalkTreeBuilder {
+            fs: Some(Arc::new(fs)),
+            ..self
+        }
+    }
+    /// Validates the walk options and returns what they resolve to.
+    pub fn walk_config(&self) -> Result<WalkConfig, WalkTreeError> {
+        let config = self.walkdir_modes.check_compability()?;
+        Ok(WalkConfig {
+            orphans: self.orphans,
+            ..config
+        })
+    }
+    /// Decides where nodes whose parent directory is not in the tree go,
+    /// `OrphanPolicy::Forest` by default.
+    pub fn with_orphans(self, orphans: OrphanPolicy) -> Self {
+        WalkTreeBuilder { orphans, ..self }
+    }
+    pub fn walk(mut self) -> Result<WalkTree<T>, WalkTreeError> {
+        let pool = self.prepare()?;
+        let mut filter = self.compose_filter()?;
+        self.walk_with(pool.as_ref(), &mut filter)
+    }
+    fn walk_with(
+        &mut self,
+        pool: Option<&ThreadPool>,
+        filter: &mut EntryFilter,
+    ) -> Result<WalkTree<T>, WalkTreeError> {
+        let (nodes, errors, stamps, dirs) = self.scan(pool, filter)?;
         let mut arena = Arena::<T>::new();
-        let map = entries
+        let map = nodes
             .into_iter()
-            .map(|e| (e.path.clone(), arena.new_node(e.data)))
+            .map(|node| (node.path, arena.new_node(node.data)))
             .collect::<BiMap<_, _>>();
 
-        Ok(WalkTree::build(arena, map))
+        let orphans = self.orphans;
+        let root = self.tree_root();
+        let tree = WalkTree::build(root, arena, map, errors, stamps, dirs, |_| orphans);
+        self.store_cache(tree, Path::new(""))
     }
-    fn apply_fiter_fn(&self, w: WalkDir) -> Vec<DirEntry> {
-        if let Some(fn_filter) = self.fn_filter {
-            w.into_iter()
-                .filter_entry(fn_filter)
-                .filter_map(|x| x.ok())
-                .collect::<Vec<_>>()
+    /// Walks and maps the entries, keyed like in `WalkTree::map`, along with
+    /// the errors, stamps and directories of the walk.
+    fn scan(
+        &mut self,
+        pool: Option<&ThreadPool>,
+        filter: &mut EntryFilter,
+    ) -> Result<Scan<T>, WalkTreeError> {
+        let (entries, mut errors) = self.apply_fiter_fn(pool, filter)?;
+        let stamps = if self.stamps || self.cache.is_some() {
+            self.stamps_of(&entries, pool)
         } else {
-            w.into_iter().filter_map(|x| x.ok()).collect::<Vec<_>>()
+            HashMap::new()
+        };
+        let dirs = self.dirs_of(&entries);
+        let entries = match self.cache.as_mut().map(|cache| cache.load()) {
+            Some(cached) => {
+                self.apply_cached_map_fn(entries, cached, &stamps, pool, &mut errors)?
+            }
+            None => self.apply_map_fn(entries, pool, &mut errors)?,
+        };
+        let mut nodes = entries
+            .into_iter()
+            .map(|e| WalkTreeNode {
+                path: self.key(&e.path),
+                data: e.data,
+            })
+            .collect();
+        if self.orphans == OrphanPolicy::Placeholder {
+            nodes = self.add_placeholders(nodes, pool, &mut errors)?;
+        }
+        Ok((nodes, errors, stamps, dirs))
+    }
+    /// The keys of the directories among `entries`.
+    pub(crate) fn dirs_of(&self, entries: &[DirEntry]) -> HashSet<PathBuf> {
+        entries
+            .iter()
+            .filter(|e| e.file_type().is_dir())
+            .map(|e| self.key(e.path()))
+            .collect()
+    }
+    /// The stamps of `entries` by key, read on `pool` if there is one.
+    pub(crate) fn stamps_of(
+        &self,
+        entries: &[DirEntry],
+        pool: Option<&ThreadPool>,
+    ) -> HashMap<PathBuf, Stamp> {
+        let stamps = match pool {
+            Some(pool) => pool.install(|| entries.par_iter().map(Stamp::of).collect()),
+            None => entries.iter().map(Stamp::of).collect::<Vec<_>>(),
+        };
+        entries
+            .iter()
+            .zip(stamps)
+            .filter_map(|(e, stamp)| Some((self.key(e.path()), stamp?)))
+            .collect()
+    }
+    /// Maps the directories missing above `nodes` and puts each in front of
+    /// the first node below it.
+    fn add_placeholders(
+        &mut self,
+        nodes: Vec<WalkTreeNode<T>>,
+        pool: Option<&ThreadPool>,
+        errors: &mut Vec<WalkTreeError>,
+    ) -> Result<Vec<WalkTreeNode<T>>, WalkTreeError> {
+        let keys = nodes
+            .iter()
+            .map(|n| n.path.as_path())
+            .collect::<HashSet<_>>();
+        let mut missing = HashSet::new();
+        let mut placeholders = Vec::new();
+        for (index, node) in nodes.iter().enumerate() {
+            let dirs = node
+                .path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| !keys.contains(dir))
+                .filter(|dir| missing.insert(*dir))
+                .collect::<Vec<_>>();
+            placeholders.extend(dirs.into_iter().rev().map(|dir| (index, dir.to_path_buf())));
+        }
+        if placeholders.is_empty() {
+            return Ok(nodes);
         }
+        let entries = placeholders
+            .iter()
+            .map(|(_, key)| {
+                let path = if key.as_os_str().is_empty() {
+                    self.root_dir.clone()
+                } else {
+                    self.root_dir.join(key)
+                };
+                let file_type = self
+                    .fs()
+                    .symlink_metadata(&path)
+                    .map_or(FileKind::Dir, |m| m.file_type());
+                let depth = key.components().count();
+                DirEntry::new(path, file_type, depth, false, self.fs.clone())
+            })
+            .collect();
+        let mut mapped = self
+            .apply_map_fn(entries, pool, errors)?
+            .into_iter()
+            .map(|node| (self.key(&node.path), node.data))
+            .collect::<HashMap<_, _>>();
+
+        let mut placeholders = placeholders.into_iter().peekable();
+        let mut filled = Vec::with_capacity(nodes.len() + mapped.len());
+        for (index, node) in nodes.into_iter().enumerate() {
+            while let Some((_, path)) = placeholders.next_if(|(i, _)| *i == index) {
+                if let Some(data) = mapped.remove(&path) {
+                    filled.push(WalkTreeNode { path, data });
+                }
+            }
+            filled.push(node);
+        }
+        Ok(filled)
     }
-    fn apply_map_fn(&self, w: Vec<DirEntry>) -> Vec<WalkTreeNode<T>> {
-        w.into_iter()
-            .map(|v| WalkTreeNode {
-                path: v.path().to_path_buf(),
-                data: self.fn_map.unwrap()(v),
+    /// Like `apply_map_fn`, but takes the item of an entry from `cached` when its stamp is unchanged.
+    fn apply_cached_map_fn(
+        &mut self,
+        entries: Vec<DirEntry>,
+        mut cached: HashMap<PathBuf, (Stamp, T)>,
+        stamps: &HashMap<PathBuf, Stamp>,
+        pool: Option<&ThreadPool>,
+        errors: &mut Vec<WalkTreeError>,
+    ) -> Result<Vec<WalkTreeNode<T>>, WalkTreeError> {
+        let mut reused = HashMap::new();
+        let mut changed = Vec::new();
+        let mut order = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let path = entry.path().to_path_buf();
+            let key = self.key(&path);
+            match (cached.remove(&key), stamps.get(&key)) {
+                (Some((old, data)), Some(stamp)) if old == *stamp => {
+                    reused.insert(path.clone(), data);
+                }
+                _ => changed.push(entry),
+            }
+            order.push(path);
+        }
+        let failed_dirs = changed
+            .iter()
+            .filter(|e| e.file_type().is_dir())
+            .map(|e| e.path().to_path_buf())
+            .collect::<Vec<_>>();
+        let mut mapped = self
+            .apply_map_fn(changed, pool, errors)?
+            .into_iter()
+            .map(|node| (node.path, node.data))
+            .collect::<HashMap<_, _>>();
+        let pruned = match self.map_error_action {
+            MapErrorAction::SkipSubtree => failed_dirs
+                .into_iter()
+                .filter(|dir| !mapped.contains_key(dir))
+                .collect(),
+            _ => HashSet::new(),
+        };
+        Ok(order
+            .into_iter()
+            .filter(|path| !path.ancestors().any(|a| pruned.contains(a)))
+            .filter_map(|path| {
+                let data = reused.remove(&path).or_else(|| mapped.remove(&path))?;
+                Some(WalkTreeNode { path, data })
             })
-            .collect::<Vec<_>>()
+            .collect())
+    }
+    /// Writes the nodes of the walked tree under `prefix` to the cache, if there is one.
+    fn store_cache(
+        &mut self,
+        mut tree: WalkTree<T>,
+        prefix: &Path,
+    ) -> Result<WalkTree<T>, WalkTreeError> {
+        if let Some(cache) = self.cache.as_mut() {
+            if let Err(err) = cache.store(&tree, prefix) {
+                self.error_policy.handle(err, &mut tree.errors)?;
+            }
+        }
+        Ok(tree)
+    }
+    /// The key of a walked `path` in `WalkTree::map`.
+    fn key(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root_dir)
+            .unwrap_or(path)
+            .to_path_buf()
+    }
+    fn tree_root(&self) -> PathBuf {
+        self.fs()
+            .canonicalize(&self.root_dir)
+            .unwrap_or_else(|_| self.root_dir.clone())
+    }
+    fn fs(&self) -> &dyn FileSystem {
+        self.fs.as_deref().unwrap_or(&RealFs)
+    }
+    /// Validates the configuration, resolves the root and returns the thread pool the walk runs on, if any.
+    fn prepare(&mut self) -> Result<Option<ThreadPool>, WalkTreeError> {
+        self.walk_config()?;
+        if self.resolve_root {
+            self.root_dir = root::resolve(&self.root_dir, self.fs())?;
+        }
+        if self.threads.is_none() && matches!(self.fn_map, Mapper::Serial(_)) {
+            return Ok(None);
+        }
+        ThreadPoolBuilder::new()
+            .num_threads(self.threads.unwrap_or(0))
+            .build()
+            .map(Some)
+            .map_err(|err| WalkTreeError::InvalidOptions(err.to_string()))
+    }
+    /// Combines the filter function with the glob and ignore-file rules.
+    fn compose_filter(&mut self) -> Result<EntryFilter, WalkTreeError> {
+        let ignore = self.ignore.map(|options| {
+            Rc::new(RefCell::new(IgnoreMatcher::new(
+                &self.root_dir,
+                options,
+                self.fs.clone(),
+            )))
+        });
+        let mut fn_filter = self.fn_filter.take();
+        if !self.include.is_empty() || !self.exclude.is_empty() {
+            let globs = GlobFilter::new(&self.root_dir, &self.include, &self.exclude)?;
+            fn_filter = and_filter(fn_filter, move |e| {
+                globs.is_match(e.path(), e.file_type().is_dir())
+            });
+        }
+        if let Some(ignore) = ignore.clone() {
+            fn_filter = and_filter(fn_filter, move |e| {
+                !ignore
+                    .borrow_mut()
+                    .is_ignored(e.path(), e.file_type().is_dir())
+            });
+        }
+        Ok(EntryFilter { fn_filter, ignore })
+    }
+    fn apply_fiter_fn(
+        &mut self,
+        pool: Option<&ThreadPool>,
+        filter: &mut EntryFilter,
+    ) -> Result<(Vec<DirEntry>, Vec<WalkTreeError>), WalkTreeError> {
+        let modes = std::mem::take(&mut self.walkdir_modes);
+        let fn_filter = &mut filter.fn_filter;
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        let mut push = |result: Result<DirEntry, WalkTreeError>| match result {
+            Ok(entry) => {
+                entries.push(entry);
+                Ok(())
+            }
+            Err(err) => self.error_policy.handle(err, &mut errors),
+        };
+        // With `ContentsFirst`, walkdir yields a directory after its contents,
+        // too late for `filter_entry` to leave them out.
+        let contents_first = modes
+            .0
+            .iter()
+            .any(|o| matches!(o, WalkDirOption::ContentsFirst));
+        let walkdir = self.fs.is_none() && !(contents_first && fn_filter.is_some());
+        match pool.filter(|_| self.threads.is_some()) {
+            None if walkdir => {
+                let iter = modes
+                    .compile_walkdir(&self.root_dir)
+                    .into_iter()
+                    .filter_entry(|e| fn_filter.as_mut().is_none_or(|f| f(&e.into())));
+                for result in iter {
+                    push(result.map(DirEntry::from).map_err(WalkTreeError::from))?;
+                }
+            }
+            pool => {
+                let walk = modes.compile_parallel(&self.root_dir, self.fs.clone());
+                walk.walk(pool, fn_filter)
+                    .into_iter()
+                    .try_for_each(&mut push)?;
+            }
+        }
+        for err in filter.take_errors() {
+            push(Err(err))?;
+        }
+        Ok((entries, errors))
+    }
+    fn apply_map_fn(
+        &mut self,
+        w: Vec<DirEntry>,
+        pool: Option<&ThreadPool>,
+        errors: &mut Vec<WalkTreeError>,
+    ) -> Result<Vec<WalkTreeNode<T>>, WalkTreeError> {
+        let mut nodes = Vec::with_capacity(w.len());
+        let mut pruned = HashSet::<PathBuf>::new();
+        let is_pruned = |path: &Path, pruned: &HashSet<PathBuf>| {
+            !pruned.is_empty() && path.ancestors().any(|a| pruned.contains(a))
+        };
+        let mut handle = |mapped: Mapped<T>, pruned: &mut HashSet<PathBuf>| {
+            let err = match mapped.result {
+                Ok(data) => {
+                    nodes.push(WalkTreeNode {
+                        path: mapped.path,
+                        data,
+                    });
+                    return Ok(());
+                }
+                Err(source) => WalkTreeError::Map {
+                    path: mapped.path.clone(),
+                    source,
+                },
+            };
+            match self.map_error_action {
+                MapErrorAction::Abort => return Err(err),
+                MapErrorAction::SkipSubtree if mapped.is_dir => {
+                    pruned.insert(mapped.path);
+                }
+                _ => {}
+            }
+            self.error_policy.handle(err, errors)
+        };
+        match (&mut self.fn_map, pool) {
+            (Mapper::Parallel(fn_map), Some(pool)) => {
+                for mapped in fn_map(w, pool) {
+                    if !is_pruned(&mapped.path, &pruned) {
+                        handle(mapped, &mut pruned)?;
+                    }
+                }
+            }
+            (Mapper::Serial(fn_map), _) => {
+                for v in w {
+                    if is_pruned(v.path(), &pruned) {
+                        continue;
+                    }
+                    let mapped = Mapped {
+                        path: v.path().to_path_buf(),
+                        is_dir: v.file_type().is_dir(),
+                        result: fn_map(v),
+                    };
+                    handle(mapped, &mut pruned)?;
+                }
+            }
+            (Mapper::Parallel(_), None) => unreachable!("parallel mapper without a thread pool"),
+        }
+        if !pruned.is_empty() {
+            // With `ContentsFirst` the children were mapped before their parent failed.
+            nodes.retain(|n| !n.path.ancestors().any(|a| pruned.contains(a)));
+        }
+        Ok(nodes)
     }
 }
 
+/// The filter function of a builder together with its glob and ignore-file rules.
+struct EntryFilter {
+    fn_filter: Option<FilterFn>,
+    ignore: Option<Rc<RefCell<IgnoreMatcher>>>,
+}
+
+impl EntryFilter {
+    fn accepts(&mut self, entry: &DirEntry) -> bool {
+        self.fn_filter.as_mut().is_none_or(|f| f(entry))
+    }
+    fn take_errors(&mut self) -> Vec<WalkTreeError> {
+        self.ignore
+            .as_ref()
+            .map(|ignore| ignore.borrow_mut().take_errors())
+            .unwrap_or_default()
+    }
+}
+
+/// Runs `first` before `filter`, which only sees the entries `first` accepts.
+fn and_filter<F>(mut filter: Option<FilterFn>, mut first: F) -> Option<FilterFn>
+where
+    F: FnMut(&DirEntry) -> bool + 'static,
+{
+    Some(Box::new(move |e| {
+        first(e) && filter.as_mut().is_none_or(|f| f(e))
+    }))
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use std::fs::read_dir;
+    use tempfile::tempdir;
 
     #[test]
     fn new() {
-        let p = Path::new("~/Documents/");
-        let _x = WalkTree::load(p)
-            .with_fliter(|_| true)
-            .with_map(|x| {
-                if x.path().is_dir() {
-                    read_dir(x.path())
-                        .unwrap()
-                        .filter_map(|x| x.ok())
-                        .map(|x| x.path())
+        let fs = MemoryFs::from_paths(["/home/docs/b.txt", "/home/docs/a/", "/home/.cache/x"])
+            .with_file("/home/docs/notes.md", "# notes")
+            .with_file("/home/docs/.ignore", "*.txt")
+            .with_symlink("/home/docs/self", ".");
+        let tree = WalkTree::load(Path::new("/home/docs"))
+            .with_file_system(fs.clone())
+            .with_walkdir_mode(WalkDirOption::SortByFileName)
+            .with_fliter(|e| e.file_name() != ".ignore")
+            .with_map(|x| (x.file_type(), x.metadata().unwrap().len()))
+            .walk()
+            .unwrap();
+        assert!(tree.errors.is_empty());
+        assert_eq!(tree.root(), Path::new("/home/docs"));
+        let root = tree.get_node_id_by_path(Path::new("")).unwrap();
+        let children = root
+            .children(&tree.arena)
+            .map(|id| tree.get_path_by_node_id(id).unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(children, ["a", "b.txt", "notes.md", "self"]);
+        assert_eq!(
+            tree.get_item_by_path(Path::new("notes.md")),
+            Some(&(FileKind::File, 7))
+        );
+        assert_eq!(
+            tree.get_item_by_path(Path::new("self")),
+            Some(&(FileKind::Symlink, 1))
+        );
+
+        let tree = WalkTree::load(Path::new("/home/docs"))
+            .with_file_system(fs.clone())
+            .with_ignore(IgnoreOptions::default())
+            .walk()
+            .unwrap();
+        assert!(tree.get_node_id_by_path(Path::new("b.txt")).is_none());
+        assert!(tree.get_node_id_by_path(Path::new("notes.md")).is_some());
+
+        let tree = WalkTree::load(Path::new("/home/docs"))
+            .with_file_system(fs)
+            .with_walkdir_mode(WalkDirOption::FollowLinks)
+            .walk()
+            .unwrap();
+        assert!(matches!(
+            tree.errors[..],
+            [WalkTreeError::SymlinkLoop { depth: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn memory_fs_inodes() {
+        let fs = MemoryFs::from_paths(["/a", "/a", "/b"]);
+        let ino = |path: &str| fs.symlink_metadata(Path::new(path)).unwrap().ino();
+        assert_ne!(ino("/a"), ino("/b"));
+    }
+
+    #[test]
+    fn error_policy() {
+        let dir = tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        let tree = WalkTree::load(&missing)
+            .with_map(|x| x.depth())
+            .walk()
+            .unwrap();
+        assert_eq!(tree.errors.len(), 1);
+        assert_eq!(tree.errors[0].path(), Some(missing.as_path()));
+
+        let tree = WalkTree::load(&missing)
+            .with_map(|x| x.depth())
+            .with_error_policy(ErrorPolicy::Ignore)
+            .walk()
+            .unwrap();
+        assert!(tree.errors.is_empty());
+
+        let err = WalkTree::load(&missing)
+            .with_map(|x| x.depth())
+            .with_error_policy(ErrorPolicy::FailFast)
+            .walk()
+            .unwrap_err();
+        assert!(matches!(err, WalkTreeError::Io { depth: 0, .. }));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_config() {
+        let config = |modes: Vec<WalkDirOption>| {
+            modes
+                .into_iter()
+                .fold(WalkTree::load(Path::new(".")), |b, mode| {
+                    b.with_walkdir_mode(mode)
+                })
+                .walk_config()
+        };
+        assert_eq!(config(vec![]).unwrap(), WalkConfig::default());
+        assert_eq!(
+            config(vec![
+                WalkDirOption::MaxDepth(2),
+                WalkDirOption::FollowLinks,
+                WalkDirOption::sort_by_key(|e| e.depth()),
+            ])
+            .unwrap(),
+            WalkConfig {
+                max_depth: 2,
+                follow_links: true,
+                sort: Some(SortOrder::Key),
+                ..WalkConfig::default()
+            }
+        );
+
+        let err = config(vec![
+            WalkDirOption::SortByFileName,
+            WalkDirOption::ContentsFirst,
+            WalkDirOption::sort_by(|a, b| a.depth().cmp(&b.depth())),
+        ])
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            WalkTreeError::ConflictingOptions {
+                first: "SortByFileName",
+                second: "SortBy"
+            }
+        ));
+        let err = config(vec![WalkDirOption::MaxDepth(1), WalkDirOption::MaxDepth(3)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid options: MaxDepth is set more than once"
+        );
+        let err = config(vec![WalkDirOption::MinDepth(3), WalkDirOption::MaxDepth(2)]).unwrap_err();
+        assert!(matches!(
+            err,
+            WalkTreeError::DepthRange {
+                min_depth: 3,
+                max_depth: 2
+            }
+        ));
+        let builder = WalkTree::load(Path::new(".")).with_walkdir_mode(WalkDirOption::MinDepth(1));
+        assert_eq!(builder.walk_config().unwrap().orphans, OrphanPolicy::Forest);
+    }
+
+    #[test]
+    fn orphans() {
+        let fs = MemoryFs::from_paths(["/r/a/b/c.txt", "/r/a/d.txt", "/r/e.txt"]);
+        let walk = |orphans, min_depth| {
+            let builder = WalkTree::load(Path::new("/r"))
+                .with_file_system(fs.clone())
+                .with_walkdir_mode(WalkDirOption::SortByFileName)
+                .with_walkdir_mode(WalkDirOption::MinDepth(min_depth))
+                .with_try_map(move |e| match e.file_name().to_str() {
+                    Some("a") if e.depth() == 1 && min_depth == 0 => Err("skipped"),
+                    name => Ok(name.unwrap().to_string()),
+                })
+                .with_orphans(orphans);
+            let tree = builder.walk().unwrap();
+            let key = |id| {
+                tree.get_path_by_node_id(id)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            };
+            let shape = tree
+                .roots()
+                .into_iter()
+                .map(|root| {
+                    root.descendants(&tree.arena)
+                        .map(|id| (key(id), tree.arena[id].parent().map(key)))
                         .collect::<Vec<_>>()
-                } else {
-                    vec![]
+                })
+                .collect::<Vec<_>>();
+            (tree.get_item_by_path(Path::new("a")).cloned(), shape)
+        };
+        let pairs = |pairs: &[(&str, Option<&str>)]| {
+            pairs
+                .iter()
+                .map(|(k, p)| (k.to_string(), p.map(str::to_string)))
+                .collect::<Vec<_>>()
+        };
+
+        let (_, shape) = walk(OrphanPolicy::Forest, 2);
+        assert_eq!(
+            shape,
+            [
+                pairs(&[("a/b", None), ("a/b/c.txt", Some("a/b"))]),
+                pairs(&[("a/d.txt", None)])
+            ]
+        );
+        let (_, shape) = walk(OrphanPolicy::Reparent, 0);
+        assert_eq!(
+            shape,
+            [pairs(&[
+                ("", None),
+                ("a/b", Some("")),
+                ("a/b/c.txt", Some("a/b")),
+                ("a/d.txt", Some("")),
+                ("e.txt", Some(""))
+            ])]
+        );
+        let (a, shape) = walk(OrphanPolicy::Placeholder, 2);
+        assert_eq!(a.as_deref(), Some("a"));
+        assert_eq!(
+            shape,
+            [pairs(&[
+                ("", None),
+                ("a", Some("")),
+                ("a/b", Some("a")),
+                ("a/b/c.txt", Some("a/b")),
+                ("a/d.txt", Some("a"))
+            ])]
+        );
+        // The placeholder for `a` cannot be mapped, so its children are reparented.
+        let (a, shape) = walk(OrphanPolicy::Placeholder, 0);
+        assert_eq!(a, None);
+        assert_eq!(shape, walk(OrphanPolicy::Reparent, 0).1);
+
+        // Without a policy, the default one leaves the orphans as roots.
+        let tree = WalkTree::load(Path::new("/r"))
+            .with_file_system(fs.clone())
+            .with_walkdir_mode(WalkDirOption::MinDepth(1))
+            .walk()
+            .unwrap();
+        assert_eq!(tree.roots().len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loop() {
+        let dir = tempdir().unwrap();
+        std::os::unix::fs::symlink(dir.path(), dir.path().join("loop")).unwrap();
+        let tree = WalkTree::load(dir.path())
+            .with_map(|x| x.depth())
+            .with_walkdir_mode(WalkDirOption::FollowLinks)
+            .walk()
+            .unwrap();
+        assert!(matches!(
+            tree.errors.as_slice(),
+            [WalkTreeError::SymlinkLoop { depth: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn stateful_closures() {
+        let dir = tempdir().unwrap();
+        for name in ["b", "a", "skip", "c"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        let ignored = ["skip".to_string()];
+        let mut counter = 0;
+        let tree = WalkTree::load(dir.path())
+            .with_fliter(move |e| !ignored.iter().any(|i| e.file_name() == i.as_str()))
+            .with_map(move |e| {
+                counter += 1;
+                (counter, e.file_name().to_string_lossy().into_owned())
+            })
+            .with_walkdir_mode(WalkDirOption::sort_by_key(|e| e.file_name().to_owned()))
+            .walk()
+            .unwrap();
+
+        let mut items = tree
+            .arena
+            .iter()
+            .map(|n| n.get().clone())
+            .collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(
+            items[1..],
+            [(2, "a".into()), (3, "b".into()), (4, "c".into())]
+        );
+    }
+
+    #[test]
+    fn default_map() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/file"), "hello").unwrap();
+
+        let tree = WalkTree::load(dir.path()).walk().unwrap();
+        let file = tree.get_item_by_path(&dir.path().join("sub/file")).unwrap();
+        assert_eq!(file.file_type, FileKind::File);
+        assert_eq!(file.depth, 2);
+        assert_eq!(file.len, 5);
+        assert!(file.modified.is_some());
+        let sub = tree.get_item_by_path(&dir.path().join("sub")).unwrap();
+        assert!(sub.file_type.is_dir());
+    }
+
+    #[test]
+    fn try_map() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("bad")).unwrap();
+        std::fs::write(dir.path().join("bad/file"), "").unwrap();
+        std::fs::write(dir.path().join("good"), "").unwrap();
+        let walk = |action| {
+            WalkTree::load(dir.path())
+                .with_try_map(|e| match e.file_name().to_str() {
+                    Some("bad") => Err("bad entry"),
+                    _ => Ok(e.depth()),
+                })
+                .on_map_error(action)
+                .walk()
+        };
+
+        let tree = walk(MapErrorAction::SkipNode).unwrap();
+        assert_eq!(tree.map.len(), 3);
+        assert!(tree
+            .get_item_by_path(&dir.path().join("bad/file"))
+            .is_some());
+        assert!(matches!(
+            tree.errors.as_slice(),
+            [WalkTreeError::Map { path, .. }] if *path == dir.path().join("bad")
+        ));
+
+        let tree = walk(MapErrorAction::SkipSubtree).unwrap();
+        assert_eq!(tree.map.len(), 2);
+        assert!(tree
+            .get_item_by_path(&dir.path().join("bad/file"))
+            .is_none());
+        assert_eq!(tree.errors.len(), 1);
+
+        let err = walk(MapErrorAction::Abort).unwrap_err();
+        assert_eq!(err.path(), Some(dir.path().join("bad").as_path()));
+    }
+
+    #[test]
+    fn parallel_walk_matches_walkdir() {
+        let dir = tempdir().unwrap();
+        for d in ["a/b/c", "a/d", "e", "f/g/h/i"] {
+            std::fs::create_dir_all(dir.path().join(d)).unwrap();
+        }
+        for f in ["a/1", "a/b/2", "a/b/c/3", "e/4", "f/g/5", "6", "f/g/h/i/7"] {
+            std::fs::write(dir.path().join(f), f).unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("a"), dir.path().join("a/b/up")).unwrap();
+
+        let options = || -> Vec<Vec<WalkDirOption>> {
+            vec![
+                vec![WalkDirOption::SortByFileName],
+                vec![WalkDirOption::ContentsFirst, WalkDirOption::SortByFileName],
+                vec![WalkDirOption::SortByFileName, WalkDirOption::FollowLinks],
+                vec![
+                    WalkDirOption::sort_by(|a, b| b.file_name().cmp(a.file_name())),
+                    WalkDirOption::MinDepth(2),
+                    WalkDirOption::MaxDepth(3),
+                ],
+            ]
+        };
+        for (sequential, parallel) in options().into_iter().zip(options()) {
+            let walk = |modes: Vec<WalkDirOption>, threads: Option<usize>| {
+                let mut builder = WalkTree::load(dir.path())
+                    .with_fliter(|e| e.file_name() != "e")
+                    .with_orphans(OrphanPolicy::Forest)
+                    .with_map(|e| (e.path().to_path_buf(), e.depth(), e.file_type()));
+                for mode in modes {
+                    builder = builder.with_walkdir_mode(mode);
+                }
+                if let Some(threads) = threads {
+                    builder = builder.with_threads(threads);
                 }
+                let tree = builder.walk().unwrap();
+                let errors = tree
+                    .errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>();
+                let nodes = tree
+                    .arena
+                    .iter()
+                    .map(|n| n.get().clone())
+                    .collect::<Vec<_>>();
+                (nodes, errors)
+            };
+            let expected = walk(sequential, None);
+            assert!(!expected.0.is_empty());
+            assert_eq!(walk(parallel, Some(4)), expected);
+        }
+
+        let tree = WalkTree::load(dir.path())
+            .with_fliter(|e| e.file_name() != "e")
+            .with_walkdir_mode(WalkDirOption::ContentsFirst)
+            .walk()
+            .unwrap();
+        assert!(tree.get_item_by_path(Path::new("e/4")).is_none());
+        assert!(tree.get_item_by_path(Path::new("f/g/5")).is_some());
+        assert!(tree.get_item_by_path(Path::new("6")).is_some());
+    }
+
+    #[test]
+    fn parallel_map() {
+        let dir = tempdir().unwrap();
+        for i in 0..20 {
+            std::fs::write(dir.path().join(i.to_string()), "x".repeat(i)).unwrap();
+        }
+        let tree = WalkTree::load(dir.path())
+            .with_walkdir_mode(WalkDirOption::SortByFileName)
+            .with_par_try_map(|e| match e.file_name().to_str() {
+                Some("13") => Err("unlucky"),
+                _ => Ok(e.metadata().unwrap().len()),
             })
             .walk()
             .unwrap();
-        // assert_eq!(x, vec![]);
+        assert_eq!(tree.map.len(), 20);
+        assert_eq!(tree.errors.len(), 1);
+        assert_eq!(
+            tree.get_item_by_path(&dir.path().join("7")).copied(),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn mutable_accessors() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("file"), "hello").unwrap();
+        let file = dir.path().join("file");
+        let mut tree = WalkTree::load(dir.path())
+            .with_map(|e| e.depth() as u64)
+            .walk()
+            .unwrap();
+
+        *tree.get_item_mut_by_path(&file).unwrap() += 10;
+        assert_eq!(tree.get_item_by_path(&file), Some(&11));
+
+        let root = *tree.get_node_id_by_path(dir.path()).unwrap();
+        *tree.get_item_mut_by_node_id(root).unwrap() = 100;
+
+        let mut visited = vec![];
+        tree.for_each_mut(|path, data| {
+            visited.push(path.to_path_buf());
+            *data *= 2;
+        });
+        assert_eq!(visited, [PathBuf::new(), PathBuf::from("file")]);
+        tree.map_in_place(|path, data| data + path.as_os_str().len() as u64);
+        assert_eq!(tree.get_item_by_path(&file), Some(&26));
+        assert_eq!(tree.get_item_by_node_id(root), Some(&200));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn relative_keys() {
+        let dir = tempdir().unwrap();
+        let real = dir.path().join("real");
+        std::fs::create_dir_all(real.join("sub")).unwrap();
+        std::fs::write(real.join("sub/file"), "").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let tree = WalkTree::load(&link).walk().unwrap();
+        assert_eq!(tree.root(), real.canonicalize().unwrap());
+        let mut keys = tree.map.left_values().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["", "sub", "sub/file"].map(PathBuf::from));
+
+        let node_id = tree.get_node_id_by_path(Path::new("sub/file"));
+        assert!(node_id.is_some());
+        assert_eq!(tree.get_node_id_by_path(&real.join("sub/file")), node_id);
+        assert_eq!(tree.get_node_id_by_path(&link.join("sub/file")), node_id);
+        assert_eq!(
+            tree.get_node_id_by_path(&tree.root().join("sub/file")),
+            node_id
+        );
+        assert!(tree.get_node_id_by_path(&link).is_some());
+        assert!(tree.get_node_id_by_path(Path::new("missing")).is_none());
+        assert!(tree.get_node_id_by_path(dir.path()).is_none());
+
+        // Relative paths are keys, whatever the working directory holds.
+        let tree = WalkTree::load(Path::new("src")).walk().unwrap();
+        assert!(tree.get_node_id_by_path(Path::new("lib.rs")).is_some());
+        assert!(tree.get_node_id_by_path(Path::new("src/lib.rs")).is_none());
+    }
+
+    #[test]
+    fn root_resolution() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("file"), "").unwrap();
+        std::env::set_var("WALKTREE_TEST_ROOT", dir.path());
+        let canonical = dir.path().join("sub").canonicalize().unwrap();
+
+        for root in ["$WALKTREE_TEST_ROOT/sub", "${WALKTREE_TEST_ROOT}/sub"] {
+            let tree = WalkTree::load(Path::new(root))
+                .with_root_resolution()
+                .walk()
+                .unwrap();
+            assert_eq!(tree.root(), canonical);
+        }
+        if let Some(home) = std::env::home_dir().filter(|home| home.is_dir()) {
+            let tree = WalkTree::load(Path::new("~"))
+                .with_walkdir_mode(WalkDirOption::MaxDepth(0))
+                .with_root_resolution()
+                .walk()
+                .unwrap();
+            assert_eq!(tree.root(), home.canonicalize().unwrap());
+        }
+
+        let file = dir.path().join("file");
+        let missing = dir.path().join("missing");
+        for root in [
+            Path::new("$WALKTREE_TEST_UNSET/sub"),
+            Path::new("~walktree_no_such_user/sub"),
+            &missing,
+            &file,
+        ] {
+            let err = WalkTree::load(root)
+                .with_root_resolution()
+                .walk()
+                .unwrap_err();
+            assert!(
+                matches!(&err, WalkTreeError::InvalidRoot { path, .. } if path == root),
+                "{}",
+                err
+            );
+        }
+
+        // Without opting in a missing root is only collected like any other error.
+        let tree = WalkTree::load(&missing).walk().unwrap();
+        assert!(matches!(tree.errors[..], [WalkTreeError::Io { .. }]));
+    }
+
+    #[test]
+    fn load_many() {
+        let dir = tempdir().unwrap();
+        for path in [
+            "src/lib.rs",
+            "src/main.rs",
+            "vendor/dep/lib.rs",
+            "gen/a/out.rs",
+        ] {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+
+        let roots = ["src", "vendor", "gen/a"].map(|root| dir.path().join(root));
+        let mut tree = WalkTree::load_many(&roots)
+            .with_each(|b| b.with_walkdir_mode(WalkDirOption::SortByFileName))
+            .with_root(&roots[0], |b| b.exclude(["main.rs"]))
+            .walk()
+            .unwrap();
+        assert_eq!(tree.root(), dir.path().canonicalize().unwrap());
+        let keys = tree
+            .node_ids()
+            .into_iter()
+            .map(|id| tree.get_path_by_node_id(id).unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "src",
+                "src/lib.rs",
+                "vendor",
+                "vendor/dep",
+                "vendor/dep/lib.rs",
+                "gen/a",
+                "gen/a/out.rs"
+            ]
+            .map(PathBuf::from)
+        );
+        let roots_of_tree = tree
+            .node_ids()
+            .into_iter()
+            .filter(|id| tree.arena[*id].parent().is_none())
+            .count();
+        assert_eq!(roots_of_tree, 3);
+        assert!(tree
+            .get_node_id_by_path(&dir.path().join("vendor/dep/lib.rs"))
+            .is_some());
+        // Refreshing from one of the roots, or from above them, would replace the whole forest.
+        for root in [&roots[0], dir.path()] {
+            let err = tree.refresh(WalkTree::load(root)).unwrap_err();
+            assert!(matches!(err, WalkTreeError::InvalidRoot { .. }), "{}", err);
+        }
+        assert_eq!(tree.map.len(), 7);
+
+        for overlapping in ["src", "src/../src", "vendor/dep", "."] {
+            let roots = [
+                dir.path().join("src"),
+                dir.path().join("vendor"),
+                dir.path().join(overlapping),
+            ];
+            let err = WalkTree::load_many(&roots).walk().unwrap_err();
+            assert!(
+                matches!(&err, WalkTreeError::InvalidRoot { path, .. } if *path == roots[2]),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn child_order() {
+        let fs = MemoryFs::from_paths(["/r/d", "/r/b", "/r/e", "/r/a", "/r/c"]);
+        let load = |fs: &MemoryFs| {
+            WalkTree::load(Path::new("/r"))
+                .with_file_system(fs.clone())
+                .with_walkdir_mode(WalkDirOption::sort_by(|a, b| {
+                    b.file_name().cmp(a.file_name())
+                }))
+        };
+        let children = |tree: &WalkTree<EntryInfo>| {
+            tree.roots()[0]
+                .children(&tree.arena)
+                .map(|id| {
+                    tree.get_path_by_node_id(id)
+                        .unwrap()
+                        .to_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let mut tree = load(&fs).walk().unwrap();
+        assert_eq!(children(&tree), ["e", "d", "c", "b", "a"]);
+        let fs = fs.with_file("/r/bb", "");
+        tree.refresh(load(&fs)).unwrap();
+        assert_eq!(children(&tree), ["e", "d", "c", "bb", "b", "a"]);
+        assert_eq!(children(&tree), children(&load(&fs).walk().unwrap()));
+
+        tree.sort_children_by(|(a, _), (b, _)| a.cmp(b));
+        assert_eq!(children(&tree), ["a", "b", "bb", "c", "d", "e"]);
+    }
+
+    #[test]
+    fn traversal() {
+        let fs = MemoryFs::from_paths(["/r/a/x", "/r/a/y/z", "/r/b", "/r/c/"]);
+        let tree = WalkTree::load(Path::new("/r"))
+            .with_file_system(fs)
+            .with_walkdir_mode(WalkDirOption::SortByFileName)
+            .walk()
+            .unwrap();
+        let keys = |visits: Vec<Visit<'_, EntryInfo>>| {
+            visits
+                .into_iter()
+                .map(|(node_id, path, info, depth)| {
+                    assert_eq!(tree.get_path_by_node_id(node_id).unwrap(), path);
+                    assert_eq!(info.depth, depth);
+                    format!("{}:{}", path.display(), depth)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            keys(tree.pre_order().collect()),
+            [":0", "a:1", "a/x:2", "a/y:2", "a/y/z:3", "b:1", "c:1"]
+        );
+        assert_eq!(
+            keys(tree.post_order().collect()),
+            ["a/x:2", "a/y/z:3", "a/y:2", "a:1", "b:1", "c:1", ":0"]
+        );
+        assert_eq!(
+            keys(tree.breadth_first().collect()),
+            [":0", "a:1", "b:1", "c:1", "a/x:2", "a/y:2", "a/y/z:3"]
+        );
+        assert_eq!(
+            keys(tree.leaves().collect()),
+            ["a/x:2", "a/y/z:3", "b:1", "c:1"]
+        );
+        assert_eq!(
+            keys(tree.children(Path::new("a")).collect()),
+            ["a/x:2", "a/y:2"]
+        );
+        assert_eq!(
+            keys(tree.ancestors(Path::new("/r/a/y/z")).collect()),
+            ["a/y:2", "a:1", ":0"]
+        );
+        assert_eq!(
+            keys(tree.siblings(Path::new("b")).collect()),
+            ["a:1", "c:1"]
+        );
+        assert_eq!(
+            keys(tree.descendants(Path::new("a")).collect()),
+            ["a/x:2", "a/y:2", "a/y/z:3"]
+        );
+        assert_eq!(tree.children(Path::new("missing")).count(), 0);
+        assert_eq!(tree.siblings(Path::new("")).count(), 0);
+    }
+
+    #[test]
+    fn subtree() {
+        let fs = MemoryFs::from_paths(["/r/a/x", "/r/a/y/z", "/r/b", "/r/c/"]);
+        let tree = WalkTree::load(Path::new("/r"))
+            .with_file_system(fs)
+            .with_walkdir_mode(WalkDirOption::SortByFileName)
+            .walk()
+            .unwrap();
+        let keys = |tree: &WalkTree<EntryInfo>| {
+            tree.pre_order()
+                .map(|(_, path, ..)| path.display().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let copy = tree.subtree(Path::new("a")).unwrap();
+        assert_eq!(copy.root(), Path::new("/r/a"));
+        assert_eq!(keys(&copy), ["", "x", "y", "y/z"]);
+        assert_eq!(copy.get_item_by_path(Path::new("y/z")).unwrap().depth, 3);
+        assert!(tree.subtree(Path::new("missing")).is_none());
+
+        let mut pruned = tree.subtree(Path::new("")).unwrap();
+        let mut removed = pruned.prune(Path::new("a/y"));
+        removed.sort();
+        assert_eq!(removed, [Path::new("a/y"), Path::new("a/y/z")]);
+        assert_eq!(keys(&pruned), ["", "a", "a/x", "b", "c"]);
+        assert_eq!(pruned.node_ids().len(), pruned.map.len());
+        assert!(pruned.prune(Path::new("a/y")).is_empty());
+
+        let mut seen = Vec::new();
+        pruned.retain(|path, _| {
+            seen.push(path.to_path_buf());
+            path != Path::new("a")
+        });
+        assert!(!seen.contains(&PathBuf::from("a/x")));
+        assert_eq!(keys(&pruned), ["", "b", "c"]);
+        assert_eq!(pruned.node_ids().len(), pruned.map.len());
+
+        let moved = tree
+            .subtree(Path::new(""))
+            .unwrap()
+            .into_subtree(Path::new("a/y"))
+            .unwrap();
+        assert_eq!(moved.root(), Path::new("/r/a/y"));
+        assert_eq!(keys(&moved), ["", "z"]);
+        assert_eq!(moved.roots().len(), 1);
+        assert_eq!(moved.arena.len(), 2);
+
+        let split = tree.into_subtrees(["a", "a/y", "missing", "b", "a"]);
+        let split = split
+            .iter()
+            .map(|tree| tree.as_ref().map(|tree| (tree.arena.len(), keys(tree))))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            split,
+            [
+                Some((2, vec!["".into(), "x".into()])),
+                Some((2, vec!["".into(), "z".into()])),
+                None,
+                Some((1, vec!["".into()])),
+                None,
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn subtree_errors() {
+        // The errors keep the paths as walked, here relative to the working directory.
+        let dir = tempdir().unwrap();
+        // Up to `/` and down to the directory, without changing the working directory.
+        let cwd = std::env::current_dir().unwrap();
+        let up = cwd.ancestors().skip(1).map(|_| "..").collect::<PathBuf>();
+        let root = &up.join(dir.path().strip_prefix("/").unwrap());
+        std::fs::create_dir_all(dir.path().join("d")).unwrap();
+        std::fs::write(dir.path().join("d/x"), "").unwrap();
+        std::fs::write(dir.path().join("e"), "").unwrap();
+        let walk = |root: &Path| {
+            WalkTree::load(root)
+                .with_try_map(|e| match e.file_name().to_str() {
+                    Some("x" | "e") => Err("failed"),
+                    _ => Ok(()),
+                })
+                .walk()
+                .unwrap()
+        };
+
+        let tree = walk(root);
+        assert_eq!(tree.errors.len(), 2);
+        let d = tree.into_subtree(Path::new("d")).unwrap();
+        assert!(matches!(
+            &d.errors[..],
+            [WalkTreeError::Map { path, .. }] if path.ends_with("d/x")
+        ));
+
+        let link = root.join("link");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+        let tree = walk(&link);
+        let d = tree.into_subtree(Path::new("d")).unwrap();
+        assert_eq!(d.errors.len(), 1);
+    }
+
+    #[test]
+    fn aggregate() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        std::fs::write(dir.path().join("a/1"), "1").unwrap();
+        std::fs::write(dir.path().join("a/b/2"), "22").unwrap();
+        std::fs::write(dir.path().join("3"), "333").unwrap();
+
+        let tree = WalkTree::load(dir.path()).walk().unwrap();
+        let totals = tree.aggregate(|info, children: &[&(u64, usize)]| {
+            let own = if info.file_type.is_file() {
+                (info.len, 1)
+            } else {
+                (0, 0)
+            };
+            children
+                .iter()
+                .fold(own, |(len, files), child| (len + child.0, files + child.1))
+        });
+
+        assert_eq!(totals.get_item_by_path(dir.path()), Some(&(6, 3)));
+        assert_eq!(
+            totals.get_item_by_path(&dir.path().join("a")),
+            Some(&(3, 2))
+        );
+        assert_eq!(
+            totals.get_item_by_path(&dir.path().join("a/b")),
+            Some(&(2, 1))
+        );
+        let a = *totals.get_node_id_by_path(&dir.path().join("a")).unwrap();
+        assert_eq!(a.children(&totals.arena).count(), 2);
+    }
+
+    #[test]
+    fn ignore_files() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        for d in [".git/info", "build/out", "sub/deep"] {
+            std::fs::create_dir_all(root.join(d)).unwrap();
+        }
+        std::fs::write(root.join(".gitignore"), "*.log\nbuild/\n!keep.log\n").unwrap();
+        std::fs::write(root.join(".ignore"), "secret\n").unwrap();
+        std::fs::write(root.join(".git/info/exclude"), "local\n").unwrap();
+        std::fs::write(root.join("sub/.gitignore"), "!debug.log\n").unwrap();
+        for f in [
+            "a.log",
+            "keep.log",
+            "secret",
+            "local",
+            "build/out/x",
+            "sub/debug.log",
+            "sub/deep/trace.log",
+        ] {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+
+        let walk = |options| {
+            let tree = WalkTree::load(root)
+                .with_fliter(|e| e.file_name() != ".git")
+                .with_ignore(options)
+                .walk()
+                .unwrap();
+            assert!(tree.errors.is_empty());
+            let mut paths = tree
+                .map
+                .left_values()
+                .filter(|p| !root.join(p).is_dir())
+                .cloned()
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        let options = IgnoreOptions {
+            git_global: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            walk(options),
+            [
+                ".gitignore",
+                ".ignore",
+                "keep.log",
+                "sub/.gitignore",
+                "sub/debug.log"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            walk(IgnoreOptions {
+                git_ignore: false,
+                ..options
+            })
+            .len(),
+            8
+        );
+    }
+
+    #[test]
+    fn globs() {
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        for d in ["src/bin", "target/debug", "docs"] {
+            std::fs::create_dir_all(root.join(d)).unwrap();
+        }
+        for f in [
+            "Cargo.toml",
+            "README.md",
+            "src/lib.rs",
+            "src/bin/main.rs",
+            "src/notes.md",
+            "target/debug/out.rs",
+            "docs/guide.md",
+            "docs/a.rs",
+            "docs/b.rs",
+        ] {
+            std::fs::write(root.join(f), "").unwrap();
+        }
+        let files = |builder: WalkTreeBuilder<EntryInfo>| {
+            let tree = builder.walk().unwrap();
+            let mut paths = tree
+                .map
+                .left_values()
+                .filter(|p| root.join(p).is_file())
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(
+            files(
+                WalkTree::load(root)
+                    .include(["**/*.rs", "*.toml"])
+                    .exclude(["target/", "docs/[a-z].rs", "!docs/b.rs"])
+            ),
+            ["Cargo.toml", "docs/b.rs", "src/bin/main.rs", "src/lib.rs"]
+        );
+        assert_eq!(
+            files(WalkTree::load(root).include(["src/", "!src/bin/"])),
+            ["src/lib.rs", "src/notes.md"]
+        );
+        assert_eq!(
+            files(WalkTree::load(root).exclude(["*.md", "{src,docs}"])),
+            ["Cargo.toml", "target/debug/out.rs"]
+        );
+        assert!(matches!(
+            WalkTree::load(root).exclude(["a[b"]).walk(),
+            Err(WalkTreeError::InvalidGlob { .. })
+        ));
+    }
+
+    #[test]
+    fn refresh() {
+        use std::{cell::Cell, fs};
+
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir_all(root.join("old/inner")).unwrap();
+        fs::write(root.join("old/inner/x"), "x").unwrap();
+        fs::write(root.join("same"), "same").unwrap();
+        fs::write(root.join("grows"), "a").unwrap();
+
+        let calls = Rc::new(Cell::new(0));
+        let builder = || {
+            let calls = calls.clone();
+            WalkTree::load(&root).with_map(move |e| {
+                calls.set(calls.get() + 1);
+                fs::read(e.path()).map(|c| c.len()).unwrap_or(0)
+            })
+        };
+        let mut tree = builder().with_stamps().walk().unwrap();
+        assert_eq!(calls.replace(0), 6);
+        assert!(!tree.stamps.is_empty());
+        let other = WalkTree::load(&root.join("old")).with_map(|_| 0);
+        assert!(matches!(
+            tree.refresh(other),
+            Err(WalkTreeError::InvalidRoot { .. })
+        ));
+
+        fs::write(root.join("grows"), "abc").unwrap();
+        fs::remove_dir_all(root.join("old")).unwrap();
+        fs::create_dir(root.join("new")).unwrap();
+        fs::write(root.join("new/y"), "yy").unwrap();
+
+        let summary = tree.refresh(builder()).unwrap();
+        let sorted = |mut paths: Vec<PathBuf>| {
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            summary.added,
+            [PathBuf::from("new"), PathBuf::from("new/y")]
+        );
+        assert_eq!(
+            sorted(summary.removed),
+            [
+                PathBuf::from("old"),
+                PathBuf::from("old/inner"),
+                PathBuf::from("old/inner/x")
+            ]
+        );
+        assert!(summary.modified.contains(&PathBuf::from("grows")));
+        assert!(!summary.modified.contains(&PathBuf::from("same")));
+        assert_eq!(calls.get(), 2 + summary.modified.len());
+
+        let shape = |tree: &WalkTree<usize>| {
+            let mut nodes = tree
+                .map
+                .iter()
+                .map(|(path, id)| {
+                    let parent = tree.arena[*id].parent();
+                    let parent = parent.and_then(|p| tree.get_path_by_node_id(p)).cloned();
+                    (path.clone(), parent, *tree.arena[*id].get())
+                })
+                .collect::<Vec<_>>();
+            nodes.sort();
+            nodes
+        };
+        let fresh = builder().walk().unwrap();
+        assert_eq!(shape(&tree), shape(&fresh));
+        assert!(fresh.stamps.is_empty());
+        assert!(tree.refresh(builder()).unwrap().is_empty());
+
+        // A directory the mapper starts failing on takes its unchanged
+        // contents along with `SkipSubtree`, as in a fresh walk.
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir(root.join("d")).unwrap();
+        fs::write(root.join("d/x"), "").unwrap();
+        let fail = Rc::new(Cell::new(false));
+        let builder = || {
+            let fail = fail.clone();
+            WalkTree::load(&root)
+                .with_try_map(move |e| match e.file_name().to_str() {
+                    Some("d") if fail.get() => Err("failed"),
+                    _ => Ok(0),
+                })
+                .on_map_error(MapErrorAction::SkipSubtree)
+        };
+        let mut tree = builder().with_stamps().walk().unwrap();
+        fail.set(true);
+        fs::write(root.join("d/y"), "").unwrap();
+        let summary = tree.refresh(builder()).unwrap();
+        assert!(summary.added.is_empty());
+        assert!(summary.modified.is_empty());
+        assert_eq!(
+            sorted(summary.removed),
+            [PathBuf::from("d"), PathBuf::from("d/x")]
+        );
+        assert_eq!(shape(&tree), shape(&builder().walk().unwrap()));
+        assert_eq!(tree.map.len(), 1);
+
+        // With `SkipNode`, its contents stay, as orphans.
+        fail.set(false);
+        fs::remove_file(root.join("d/y")).unwrap();
+        let builder = || builder().on_map_error(MapErrorAction::SkipNode);
+        let mut tree = builder().with_stamps().walk().unwrap();
+        fail.set(true);
+        fs::write(root.join("d/y"), "").unwrap();
+        let summary = tree.refresh(builder()).unwrap();
+        assert_eq!(summary.removed, [PathBuf::from("d")]);
+        assert_eq!(shape(&tree), shape(&builder().walk().unwrap()));
+        assert_eq!(tree.roots().len(), 3);
+    }
+
+    #[test]
+    fn diff() {
+        use std::fs::{create_dir, read_to_string, remove_file, rename, write};
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        write(root.join("a"), "1").unwrap();
+        write(root.join("b"), "2").unwrap();
+        create_dir(root.join("d")).unwrap();
+        write(root.join("d/x"), "3").unwrap();
+        write(root.join("d/y"), "4").unwrap();
+        let snapshot = || {
+            WalkTree::load(root)
+                .with_map(|e| read_to_string(e.path()).unwrap_or_default())
+                .walk()
+                .unwrap()
+        };
+        let before = snapshot();
+        assert!(before.diff(&before).is_empty());
+
+        write(root.join("a"), "5").unwrap();
+        remove_file(root.join("b")).unwrap();
+        write(root.join("c"), "4").unwrap();
+        rename(root.join("d"), root.join("e")).unwrap();
+        let after = snapshot();
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.modified, [PathBuf::from("a")]);
+        assert_eq!(diff.removed, [PathBuf::from("b")]);
+        assert_eq!(diff.added, [PathBuf::from("c")]);
+        assert_eq!(diff.moved, [(PathBuf::from("d"), PathBuf::from("e"))]);
+
+        let diff = before.diff_by(&after, |a, b| a.len() == b.len());
+        assert!(diff.modified.is_empty() && diff.added.is_empty());
+        assert_eq!(
+            diff.moved,
+            [
+                (PathBuf::from("b"), PathBuf::from("c")),
+                (PathBuf::from("d"), PathBuf::from("e"))
+            ]
+        );
+    }
+
+    #[test]
+    fn render() {
+        use std::fs::{create_dir_all, write};
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("root");
+        create_dir_all(root.join("b/c")).unwrap();
+        write(root.join("a"), "").unwrap();
+        write(root.join("b/x"), "").unwrap();
+        write(root.join("b/c/y"), "").unwrap();
+        write(root.join("z"), "").unwrap();
+        let tree = WalkTree::load(&root)
+            .with_walkdir_mode(WalkDirOption::SortByFileName)
+            .walk()
+            .unwrap();
+
+        assert_eq!(
+            tree.render().to_string(),
+            "root\n\
+             ├── a\n\
+             ├── b\n\
+             │   ├── c\n\
+             │   │   └── y\n\
+             │   └── x\n\
+             └── z\n\
+             \n\
+             2 directories, 4 files\n"
+        );
+        assert_eq!(
+            tree.render()
+                .with_charset(Charset::Ascii)
+                .with_max_depth(1)
+                .with_dirs_first()
+                .with_label(|_, e| format!(
+                    "{} ({})",
+                    e.path.file_name().unwrap().to_string_lossy(),
+                    e.depth
+                ))
+                .with_is_dir(|_, e| e.file_type.is_dir())
+                .to_string(),
+            "root (0)\n\
+             |-- b (1)\n\
+             |-- a (1)\n\
+             `-- z (1)\n\
+             \n\
+             1 directory, 2 files\n"
+        );
+
+        let tree = WalkTree::load(Path::new("/m"))
+            .with_file_system(MemoryFs::from_paths(["/m/d/x", "/m/e/", "/m/f"]))
+            .walk()
+            .unwrap();
+        let summary =
+            |render: Render<'_, EntryInfo>| render.to_string().lines().last().unwrap().to_string();
+        assert_eq!(summary(tree.render()), "2 directories, 2 files");
+        assert_eq!(
+            summary(tree.render().with_is_dir(|_, e| e.file_type.is_dir())),
+            "2 directories, 2 files"
+        );
+        let tree = WalkTree::load(Path::new("/m"))
+            .with_file_system(MemoryFs::from_paths(["/m/d/x", "/m/e/", "/m/f"]))
+            .with_walkdir_mode(WalkDirOption::MaxDepth(1))
+            .walk()
+            .unwrap();
+        assert_eq!(summary(tree.render()), "2 directories, 1 file");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        use std::fs::{create_dir, write};
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        create_dir(root.join("d")).unwrap();
+        write(root.join("d/x"), "").unwrap();
+        write(root.join("a"), "").unwrap();
+        let tree = WalkTree::load(root)
+            .with_map(|e| e.file_name().to_string_lossy().into_owned())
+            .walk()
+            .unwrap();
+
+        let json = serde_json::to_value(&tree).unwrap();
+        assert_eq!(json["root"], root.to_str().unwrap());
+        assert_eq!(json["nodes"][0]["path"], "");
+        assert_eq!(json["nodes"][0]["children"].as_array().unwrap().len(), 2);
+        let mut duplicate = json.clone();
+        duplicate["nodes"][0]["children"][1]["path"] =
+            json["nodes"][0]["children"][0]["path"].clone();
+        assert!(serde_json::from_value::<WalkTree<String>>(duplicate).is_err());
+        let loaded: WalkTree<String> = serde_json::from_value(json).unwrap();
+        assert!(loaded == tree);
+
+        let json = flat::serialize(&tree, serde_json::value::Serializer).unwrap();
+        assert_eq!(json["nodes"]["d/x"], "x");
+        let items = |tree: &WalkTree<String>| {
+            let mut items = tree
+                .pre_order()
+                .map(|(_, path, item, depth)| (path.to_path_buf(), item.clone(), depth))
+                .collect::<Vec<_>>();
+            items.sort();
+            items
+        };
+        assert_eq!(items(&flat::deserialize(json).unwrap()), items(&tree));
+
+        let json = compact::serialize(&tree, serde_json::value::Serializer).unwrap();
+        assert_eq!(json[1].as_array().unwrap().len(), 4);
+        assert!(compact::deserialize::<String, _>(json).unwrap() == tree);
+
+        let bytes = tree.to_bytes().unwrap();
+        assert!(WalkTree::<String>::from_bytes(&bytes).unwrap() == tree);
+        assert!(matches!(
+            WalkTree::<String>::from_bytes(&bytes[..3]),
+            Err(WalkTreeError::Encoding(_))
+        ));
+
+        // The arena is not in pre-order here, nor are the children in it.
+        let mut tree = WalkTree::load(root)
+            .with_walkdir_mode(WalkDirOption::ContentsFirst)
+            .with_map(|e| e.file_name().to_string_lossy().into_owned())
+            .walk()
+            .unwrap();
+        tree.sort_children_by(|(a, _), (b, _)| b.cmp(a));
+        let mut json = serde_json::to_value(&tree).unwrap();
+        assert!(serde_json::from_value::<WalkTree<String>>(json.clone()).unwrap() == tree);
+        let compact = compact::serialize(&tree, serde_json::value::Serializer).unwrap();
+        assert!(compact::deserialize::<String, _>(compact).unwrap() == tree);
+        let bytes = tree.to_bytes().unwrap();
+        assert!(WalkTree::<String>::from_bytes(&bytes).unwrap() == tree);
+        json["nodes"][0]["id"] = json["nodes"][0]["children"][0]["id"].clone();
+        assert!(serde_json::from_value::<WalkTree<String>>(json).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn cache() {
+        use std::{cell::Cell, fs::write};
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("d")).unwrap();
+        write(root.join("a"), "1").unwrap();
+        write(root.join("d/x"), "22").unwrap();
+        let cache = dir.path().join("cache");
+        let calls = Rc::new(Cell::new(0));
+        let walk = || {
+            let calls = calls.clone();
+            WalkTree::load(&root)
+                .with_map(move |e| {
+                    calls.set(calls.get() + 1);
+                    e.metadata().unwrap().len()
+                })
+                .with_cache(&cache)
+                .walk()
+                .unwrap()
+        };
+
+        let first = walk();
+        assert_eq!(calls.replace(0), 4);
+        assert!(cache.exists());
+        assert!(walk() == first);
+        assert_eq!(calls.replace(0), 0);
+
+        write(root.join("a"), "333").unwrap();
+        let tree = walk();
+        assert_eq!(calls.replace(0), 1);
+        assert_eq!(tree.get_item_by_path(&root.join("a")), Some(&3));
+        assert_eq!(tree.get_item_by_path(&root.join("d/x")), Some(&2));
+
+        write(&cache, "garbage").unwrap();
+        walk();
+        assert_eq!(calls.replace(0), 4);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watch() {
+        use std::fs::{create_dir, remove_file, rename, write};
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        write(root.join("a"), "1").unwrap();
+        create_dir(root.join("sub")).unwrap();
+        write(root.join("skip.log"), "").unwrap();
+
+        let mut watcher = WalkTree::load(root)
+            .with_map(|e| e.metadata().map(|m| m.len()).unwrap_or(0))
+            .exclude(["**/*.log"])
+            .watch()
+            .unwrap();
+        let events = watcher.subscribe();
+        assert!(watcher.poll().unwrap().is_empty());
+
+        let mut changes = Vec::new();
+        let mut settle = |watcher: &mut Watcher<u64>, count: usize| {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+            while changes.len() < count && std::time::Instant::now() < deadline {
+                changes.extend(watcher.poll().unwrap());
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            std::mem::take(&mut changes)
+        };
+
+        write(root.join("a"), "1234").unwrap();
+        create_dir(root.join("sub/new")).unwrap();
+        write(root.join("sub/new/b"), "12").unwrap();
+        write(root.join("sub/c.log"), "").unwrap();
+        let mut first = settle(&mut watcher, 3);
+        first.sort_by_key(|c| format!("{c:?}"));
+        assert_eq!(
+            first,
+            [
+                ChangeEvent::Created(PathBuf::from("sub/new")),
+                ChangeEvent::Created(PathBuf::from("sub/new/b")),
+                ChangeEvent::Modified(PathBuf::from("a")),
+            ]
+        );
+
+        rename(root.join("sub"), root.join("moved")).unwrap();
+        remove_file(root.join("skip.log")).unwrap();
+        let second = settle(&mut watcher, 1);
+        assert_eq!(
+            second,
+            [ChangeEvent::Renamed {
+                from: PathBuf::from("sub"),
+                to: PathBuf::from("moved"),
+            }]
+        );
+        assert_eq!(events.try_iter().count(), 4);
+
+        let tree = watcher.tree();
+        assert_eq!(tree.get_item_by_path(&root.join("a")), Some(&4));
+        assert_eq!(tree.get_item_by_path(&root.join("moved/new/b")), Some(&2));
+        assert!(tree.get_item_by_path(&root.join("sub")).is_none());
+        assert!(tree.get_item_by_path(&root.join("moved/c.log")).is_none());
+        let moved = *tree.get_node_id_by_path(&root.join("moved")).unwrap();
+        let parent = tree.arena[moved].parent().unwrap();
+        assert_eq!(tree.get_path_by_node_id(parent), Some(&PathBuf::new()));
+        assert_eq!(tree.map.len(), 5);
+        assert!(watcher.poll().unwrap().is_empty());
+
+        // A directory the mapper starts failing on leaves its contents as orphans.
+        let dir = tempdir().unwrap();
+        let root = dir.path();
+        create_dir(root.join("d")).unwrap();
+        write(root.join("d/x"), "").unwrap();
+        let fail = Rc::new(std::cell::Cell::new(false));
+        let mut watcher = WalkTree::load(root)
+            .with_try_map({
+                let fail = fail.clone();
+                move |e| match e.file_name().to_str() {
+                    Some("d") if fail.get() => Err("failed"),
+                    _ => Ok(0),
+                }
+            })
+            .watch()
+            .unwrap();
+        fail.set(true);
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        std::fs::File::open(root.join("d"))
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert_eq!(
+            settle(&mut watcher, 1),
+            [ChangeEvent::Removed(PathBuf::from("d"))]
+        );
+        let tree = watcher.tree();
+        let x = *tree.get_node_id_by_path(Path::new("d/x")).unwrap();
+        assert!(tree.arena[x].parent().is_none());
     }
 }
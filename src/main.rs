@@ -0,0 +1,179 @@
+use clap::{Parser, ValueEnum};
+use std::{
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+use walktree::{
+    Charset, EntryInfo, IgnoreOptions, OrphanPolicy, WalkDirOption, WalkTree, WalkTreeBuilder,
+};
+
+/// Walks a directory and prints it as a tree, JSON or NDJSON.
+#[derive(Parser)]
+#[command(name = "walktree", version)]
+struct Args {
+    /// Directory to walk.
+    #[arg(default_value = ".")]
+    path: PathBuf,
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Do not descend more than this many levels below the root.
+    #[arg(short = 'L', long)]
+    max_depth: Option<usize>,
+    /// Leave out entries less than this many levels below the root.
+    #[arg(long)]
+    min_depth: Option<usize>,
+    /// Where entries go whose parent directory was left out.
+    #[arg(long, value_enum, default_value_t = Orphans::Forest)]
+    orphans: Orphans,
+    /// Follow symbolic links.
+    #[arg(short = 'l', long)]
+    follow_links: bool,
+    /// Do not cross file system boundaries.
+    #[arg(short = 'x', long)]
+    same_file_system: bool,
+    /// List the contents of a directory before the directory itself.
+    #[arg(long)]
+    contents_first: bool,
+    #[arg(short, long, value_enum)]
+    sort: Option<Sort>,
+    /// Only keep files matching this glob; may be repeated.
+    #[arg(short = 'i', long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Skip entries matching this glob; may be repeated.
+    #[arg(short = 'e', long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Skip entries ignored by .gitignore, .ignore and git's exclude files.
+    #[arg(short = 'g', long)]
+    gitignore: bool,
+    /// Read directories on this many threads, 0 for one per CPU.
+    #[arg(short = 'j', long)]
+    threads: Option<usize>,
+    /// Draw the text tree with ASCII instead of Unicode characters.
+    #[arg(long)]
+    ascii: bool,
+    /// List directories before files in the text tree.
+    #[arg(long)]
+    dirs_first: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Orphans {
+    /// Attach them to their nearest listed ancestor.
+    Reparent,
+    /// List the missing directories too.
+    Placeholder,
+    /// List them as separate trees.
+    Forest,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Sort {
+    Name,
+    Size,
+    Modified,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let tree = match builder(&args).walk() {
+        Ok(tree) => tree,
+        Err(err) => {
+            eprintln!("walktree: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut out = BufWriter::new(io::stdout().lock());
+    if let Err(err) = print(&args, &tree, &mut out).and_then(|()| out.flush()) {
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("walktree: {}", err);
+            return ExitCode::FAILURE;
+        }
+    }
+    for err in &tree.errors {
+        eprintln!("walktree: {}", err);
+    }
+    if tree.errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn builder(args: &Args) -> WalkTreeBuilder<EntryInfo> {
+    let mut builder = WalkTree::load(&args.path)
+        .include(&args.include)
+        .exclude(&args.exclude)
+        .with_orphans(match args.orphans {
+            Orphans::Reparent => OrphanPolicy::Reparent,
+            Orphans::Placeholder => OrphanPolicy::Placeholder,
+            Orphans::Forest => OrphanPolicy::Forest,
+        });
+    let modes = [
+        args.max_depth.map(WalkDirOption::MaxDepth),
+        args.min_depth.map(WalkDirOption::MinDepth),
+        args.follow_links.then_some(WalkDirOption::FollowLinks),
+        args.same_file_system
+            .then_some(WalkDirOption::SameFileSystem),
+        args.contents_first.then_some(WalkDirOption::ContentsFirst),
+        args.sort.map(|sort| match sort {
+            Sort::Name => WalkDirOption::SortByFileName,
+            Sort::Size => WalkDirOption::sort_by_key(|e| e.metadata().map(|m| m.len()).ok()),
+            Sort::Modified => {
+                WalkDirOption::sort_by_key(|e| e.metadata().and_then(|m| m.modified()).ok())
+            }
+        }),
+    ];
+    for mode in modes.into_iter().flatten() {
+        builder = builder.with_walkdir_mode(mode);
+    }
+    if args.gitignore {
+        builder = builder.with_ignore(IgnoreOptions::default());
+    }
+    if let Some(threads) = args.threads {
+        builder = builder.with_threads(threads);
+    }
+    builder
+}
+
+fn print(args: &Args, tree: &WalkTree<EntryInfo>, out: &mut impl Write) -> io::Result<()> {
+    match args.format {
+        Format::Text => {
+            let charset = if args.ascii {
+                Charset::Ascii
+            } else {
+                Charset::Unicode
+            };
+            let mut render = tree
+                .render()
+                .with_charset(charset)
+                .with_is_dir(|_, e| e.file_type.is_dir())
+                .with_label(|path, e| match path.file_name() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => e.path.display().to_string(),
+                });
+            if args.dirs_first {
+                render = render.with_dirs_first();
+            }
+            write!(out, "{}", render)
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, tree).map_err(io::Error::from)?;
+            writeln!(out)
+        }
+        Format::Ndjson => {
+            for node in tree.arena.iter().filter(|node| !node.is_removed()) {
+                serde_json::to_writer(&mut *out, node.get()).map_err(io::Error::from)?;
+                writeln!(out)?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use crate::{DirEntry, FileKind, FileSystem, FilterFn, Metadata, RealFs, SortFn, WalkTreeError};
+use rayon::ThreadPool;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+};
+
+type Walked = Result<DirEntry, WalkTreeError>;
+
+pub(crate) enum Sorter {
+    FileName,
+    By(SortFn),
+}
+
+/// Multi-threaded counterpart of `walkdir::WalkDir`, which also walks
+/// filesystems other than the disk.
+///
+/// Each directory is read by a task of its own on a thread pool, or on the
+/// calling thread without one, while filtering and sorting happen on the
+/// calling thread as the listings arrive. The listings are then stitched back
+/// together in the exact order `walkdir` would have produced, so the result
+/// does not depend on thread scheduling.
+pub(crate) struct ParallelWalk {
+    root: PathBuf,
+    fs: Option<Arc<dyn FileSystem>>,
+    pub(crate) contents_first: bool,
+    pub(crate) follow_links: bool,
+    pub(crate) max_depth: usize,
+    pub(crate) min_depth: usize,
+    pub(crate) same_file_system: bool,
+    pub(crate) sorter: Option<Sorter>,
+}
+
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = PathBuf;
+
+struct Ancestor {
+    path: PathBuf,
+    id: FileId,
+    parent: Option<Arc<Ancestor>>,
+}
+
+struct Item {
+    result: Walked,
+    children: Vec<usize>,
+}
+
+/// The listings filed so far, in the order `flatten` expects.
+struct Listed {
+    items: Vec<Item>,
+    sorter: Option<Sorter>,
+    root_device: Option<u64>,
+}
+
+struct Pending {
+    item: usize,
+    path: PathBuf,
+    depth: usize,
+    ancestors: Option<Arc<Ancestor>>,
+}
+
+struct Child {
+    result: Walked,
+    id: Option<FileId>,
+    device: Option<u64>,
+}
+
+impl Child {
+    fn err(err: WalkTreeError) -> Self {
+        Child {
+            result: Err(err),
+            id: None,
+            device: None,
+        }
+    }
+}
+
+impl ParallelWalk {
+    pub(crate) fn new(root: &Path, fs: Option<Arc<dyn FileSystem>>) -> Self {
+        ParallelWalk {
+            root: root.to_path_buf(),
+            fs,
+            contents_first: false,
+            follow_links: false,
+            max_depth: usize::MAX,
+            min_depth: 0,
+            same_file_system: false,
+            sorter: None,
+        }
+    }
+
+    pub(crate) fn walk(
+        mut self,
+        pool: Option<&ThreadPool>,
+        filter: &mut Option<FilterFn>,
+    ) -> Vec<Walked> {
+        let min_depth = self.min_depth;
+        let mut keep = |e: &DirEntry| e.depth() < min_depth || filter.as_mut().is_none_or(|f| f(e));
+        let (root, descend) = match self.root_entry() {
+            Ok(root) => root,
+            Err(err) => return vec![Err(err)],
+        };
+        if !keep(root.result.as_ref().unwrap()) {
+            return Vec::new();
+        }
+        let root_dir = (descend && self.max_depth > 0).then(|| Pending {
+            item: 0,
+            path: self.root.clone(),
+            depth: 0,
+            ancestors: root.id.map(|id| {
+                Arc::new(Ancestor {
+                    path: self.root.clone(),
+                    id,
+                    parent: None,
+                })
+            }),
+        });
+        let mut listed = Listed {
+            items: vec![Item {
+                result: root.result,
+                children: Vec::new(),
+            }],
+            sorter: self.sorter.take(),
+            root_device: root.device,
+        };
+        let this = &self;
+        let mut expand =
+            |dir: &Pending, children| this.expand(&mut listed, dir, children, &mut keep);
+
+        match (pool, root_dir) {
+            (_, None) => {}
+            // Every directory is read by a task of its own, so idle threads
+            // steal work as soon as any listing arrives.
+            (Some(pool), Some(root_dir)) => pool.in_place_scope(|scope| {
+                let (tx, rx) = mpsc::channel();
+                let read = |dir: Pending| {
+                    let tx = tx.clone();
+                    scope.spawn(move |_| {
+                        let children = this.read_children(&dir);
+                        tx.send((dir, children)).ok();
+                    });
+                };
+                read(root_dir);
+                let mut reading = 1;
+                while reading > 0 {
+                    let (dir, children) = rx.recv().unwrap();
+                    reading -= 1;
+                    for next in expand(&dir, children) {
+                        read(next);
+                        reading += 1;
+                    }
+                }
+            }),
+            (None, Some(root_dir)) => {
+                let mut stack = vec![root_dir];
+                while let Some(dir) = stack.pop() {
+                    let children = this.read_children(&dir);
+                    stack.extend(expand(&dir, children).into_iter().rev());
+                }
+            }
+        }
+        self.flatten(listed.items)
+    }
+
+    /// Files the listing of `dir` below its item and returns the directories
+    /// to read next.
+    fn expand<F>(
+        &self,
+        listed: &mut Listed,
+        dir: &Pending,
+        children: Vec<Child>,
+        keep: &mut F,
+    ) -> Vec<Pending>
+    where
+        F: FnMut(&DirEntry) -> bool,
+    {
+        let (errors, mut children): (Vec<_>, Vec<_>) =
+            children.into_iter().partition(|c| c.result.is_err());
+        sort(&mut listed.sorter, &mut children);
+        let mut next = Vec::new();
+        for child in errors.into_iter().chain(children) {
+            let descend = match &child.result {
+                Err(_) => false,
+                Ok(e) if !keep(e) => continue,
+                Ok(e) => {
+                    e.file_type().is_dir()
+                        && e.depth() < self.max_depth
+                        && (!self.same_file_system || child.device == listed.root_device)
+                }
+            };
+            let index = listed.items.len();
+            listed.items[dir.item].children.push(index);
+            if descend {
+                let path = child.result.as_ref().unwrap().path().to_path_buf();
+                next.push(Pending {
+                    item: index,
+                    ancestors: child.id.map(|id| {
+                        Arc::new(Ancestor {
+                            path: path.clone(),
+                            id,
+                            parent: dir.ancestors.clone(),
+                        })
+                    }),
+                    path,
+                    depth: dir.depth + 1,
+                });
+            }
+            listed.items.push(Item {
+                result: child.result,
+                children: Vec::new(),
+            });
+        }
+        next
+    }
+
+    fn fs(&self) -> &dyn FileSystem {
+        self.fs.as_deref().unwrap_or(&RealFs)
+    }
+
+    fn root_entry(&self) -> Result<(Child, bool), WalkTreeError> {
+        let root = &self.root;
+        let io_err = |source| WalkTreeError::Io {
+            path: Some(root.clone()),
+            depth: 0,
+            source,
+        };
+        let metadata = self.fs().symlink_metadata(root).map_err(io_err)?;
+        let mut file_type = metadata.file_type();
+        let mut follow_link = false;
+        // Like walkdir, a symlinked root is always descended into.
+        let target = if file_type.is_symlink() {
+            let target = self.fs().metadata(root).map_err(io_err)?;
+            if self.follow_links {
+                file_type = target.file_type();
+                follow_link = true;
+            }
+            target
+        } else {
+            metadata
+        };
+        let id = if self.follow_links && target.is_dir() {
+            Some(file_id(self.fs(), root, &target).map_err(io_err)?)
+        } else {
+            None
+        };
+        let entry = DirEntry::new(root.clone(), file_type, 0, follow_link, self.fs.clone());
+        let child = Child {
+            result: Ok(entry),
+            id,
+            device: device(&target),
+        };
+        Ok((child, target.is_dir()))
+    }
+
+    fn read_children(&self, dir: &Pending) -> Vec<Child> {
+        let depth = dir.depth + 1;
+        match self.fs().read_dir(&dir.path) {
+            Ok(read_dir) => read_dir
+                .into_iter()
+                .map(|result| match result {
+                    Ok((path, file_type)) => self.read_child(path, file_type, depth, dir),
+                    Err(source) => Child::err(WalkTreeError::Io {
+                        path: None,
+                        depth,
+                        source,
+                    }),
+                })
+                .collect(),
+            Err(source) => vec![Child::err(WalkTreeError::Io {
+                path: Some(dir.path.clone()),
+                depth: dir.depth,
+                source,
+            })],
+        }
+    }
+
+    fn read_child(&self, path: PathBuf, file_type: FileKind, depth: usize, dir: &Pending) -> Child {
+        let follow_link = self.follow_links && file_type.is_symlink();
+        let needs_metadata =
+            follow_link || (file_type.is_dir() && (self.follow_links || self.same_file_system));
+        let metadata = if needs_metadata {
+            match self.fs().metadata(&path) {
+                Ok(metadata) => Some(metadata),
+                Err(source) => {
+                    return Child::err(WalkTreeError::Io {
+                        path: Some(path),
+                        depth,
+                        source,
+                    })
+                }
+            }
+        } else {
+            None
+        };
+        let file_type = match (&metadata, follow_link) {
+            (Some(metadata), true) => metadata.file_type(),
+            _ => file_type,
+        };
+        let mut id = None;
+        if let (Some(metadata), true) = (&metadata, self.follow_links && file_type.is_dir()) {
+            match file_id(self.fs(), &path, metadata) {
+                Ok(file_id) => id = Some(file_id),
+                Err(source) => {
+                    return Child::err(WalkTreeError::Io {
+                        path: Some(path),
+                        depth,
+                        source,
+                    })
+                }
+            }
+        }
+        if let (true, Some(child_id)) = (follow_link, &id) {
+            let mut ancestor = dir.ancestors.as_deref();
+            while let Some(a) = ancestor {
+                if a.id == *child_id {
+                    return Child::err(WalkTreeError::SymlinkLoop {
+                        ancestor: a.path.clone(),
+                        child: path,
+                        depth,
+                    });
+                }
+                ancestor = a.parent.as_deref();
+            }
+        }
+        Child {
+            result: Ok(DirEntry::new(
+                path,
+                file_type,
+                depth,
+                follow_link,
+                self.fs.clone(),
+            )),
+            id,
+            device: metadata.as_ref().and_then(device),
+        }
+    }
+
+    fn flatten(&self, items: Vec<Item>) -> Vec<Walked> {
+        let mut slots = Vec::with_capacity(items.len());
+        let mut children = Vec::with_capacity(items.len());
+        for item in items {
+            slots.push(Some(item.result));
+            children.push(item.children);
+        }
+        let mut walked = Vec::with_capacity(slots.len());
+        let mut stack = vec![(0, false)];
+        while let Some((index, expanded)) = stack.pop() {
+            let deferred = self.contents_first
+                && matches!(&slots[index], Some(Ok(e)) if e.file_type().is_dir());
+            if !expanded && deferred {
+                stack.push((index, true));
+            }
+            if expanded || !deferred {
+                match slots[index].take() {
+                    Some(Ok(e)) if e.depth() < self.min_depth => {}
+                    Some(result) => walked.push(result),
+                    None => {}
+                }
+            }
+            if !expanded {
+                stack.extend(children[index].iter().rev().map(|&c| (c, false)));
+            }
+        }
+        walked
+    }
+}
+
+fn sort(sorter: &mut Option<Sorter>, children: &mut [Child]) {
+    fn entry(c: &Child) -> &DirEntry {
+        c.result.as_ref().unwrap()
+    }
+    match sorter {
+        Some(Sorter::FileName) => {
+            children.sort_by(|a, b| entry(a).file_name().cmp(entry(b).file_name()))
+        }
+        Some(Sorter::By(f)) => children.sort_by(|a, b| f(entry(a), entry(b))),
+        None => {}
+    }
+}
+
+#[cfg(unix)]
+fn file_id(_fs: &dyn FileSystem, _path: &Path, metadata: &Metadata) -> io::Result<FileId> {
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(fs: &dyn FileSystem, path: &Path, _metadata: &Metadata) -> io::Result<FileId> {
+    fs.canonicalize(path)
+}
+
+#[cfg(unix)]
+fn device(metadata: &Metadata) -> Option<u64> {
+    Some(metadata.dev())
+}
+
+// Without device numbers every directory counts as being on the root's file system.
+#[cfg(not(unix))]
+fn device(_metadata: &Metadata) -> Option<u64> {
+    None
+}
@@ -0,0 +1,195 @@
+use crate::{MapErrorAction, OrphanPolicy, WalkTree, WalkTreeBuilder, WalkTreeError};
+use indextree::NodeId;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+/// Paths that changed on disk since the tree was walked or last refreshed,
+/// relative to the root like the keys of `WalkTree::map`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSummary {
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+}
+
+impl ChangeSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl<T> WalkTree<T> {
+    /// Brings the tree up to date with the filesystem.
+    ///
+    /// `builder` is walked again, but its mapper only runs for entries that
+    /// are new or whose size, modification time or inode changed. A tree
+    /// walked without `WalkTreeBuilder::with_stamps` has none recorded, so
+    /// its first refresh maps every entry again. Entries
+    /// that disappeared are removed from `arena` and `map`, and `errors` is
+    /// replaced by the errors of this walk. Placeholders of
+    /// `OrphanPolicy::Placeholder` stay while anything below them is walked;
+    /// new orphans are attached to their nearest ancestor instead.
+    ///
+    /// `builder` must walk the root of the tree, so a tree loaded from
+    /// several roots cannot be refreshed; that fails with
+    /// `WalkTreeError::InvalidRoot`.
+    pub fn refresh(
+        &mut self,
+        mut builder: WalkTreeBuilder<T>,
+    ) -> Result<ChangeSummary, WalkTreeError> {
+        let pool = builder.prepare()?;
+        let root = builder.tree_root();
+        if self.loaded_many {
+            return Err(WalkTreeError::InvalidRoot {
+                path: root,
+                reason: "the tree was loaded from several roots".to_string(),
+            });
+        }
+        if root != self.root {
+            return Err(WalkTreeError::InvalidRoot {
+                path: root,
+                reason: format!("is not the root {} of the tree", self.root.display()),
+            });
+        }
+        let mut filter = builder.compose_filter()?;
+        let (entries, mut errors) = builder.apply_fiter_fn(pool.as_ref(), &mut filter)?;
+
+        let mut summary = ChangeSummary::default();
+        let stamps = builder.stamps_of(&entries, pool.as_ref());
+        let dirs = builder.dirs_of(&entries);
+        let mut seen = HashSet::with_capacity(entries.len());
+        let mut changed = Vec::new();
+        let mut order = HashMap::with_capacity(entries.len());
+        for (index, entry) in entries.into_iter().enumerate() {
+            let path = builder.key(entry.path());
+            order.insert(path.clone(), index);
+            let stamp = stamps.get(&path);
+            if !self.map.contains_left(&path) {
+                summary.added.push(path.clone());
+                changed.push(entry);
+            } else if stamp.is_none() || self.stamps.get(&path) != stamp {
+                summary.modified.push(path.clone());
+                changed.push(entry);
+            }
+            seen.insert(path);
+        }
+        if builder.orphans == OrphanPolicy::Placeholder {
+            // Placeholders stay as long as something below them was walked.
+            let walked = seen.iter().cloned().collect::<Vec<_>>();
+            seen.extend(
+                walked
+                    .iter()
+                    .flat_map(|p| p.ancestors().skip(1))
+                    .map(Path::to_path_buf),
+            );
+        }
+        summary.removed = self
+            .map
+            .left_values()
+            .filter(|path| !seen.contains(*path))
+            .cloned()
+            .collect();
+        let mut parents = Vec::new();
+        for path in &summary.removed {
+            parents.extend(self.remove_node(path, builder.orphans));
+        }
+
+        let changed_dirs = changed
+            .iter()
+            .filter(|e| e.file_type().is_dir())
+            .map(|e| builder.key(e.path()))
+            .collect::<HashSet<_>>();
+        let nodes = builder.apply_map_fn(changed, pool.as_ref(), &mut errors)?;
+        let nodes = nodes
+            .into_iter()
+            .map(|n| (builder.key(&n.path), n.data))
+            .collect::<Vec<_>>();
+        let mapped = nodes
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<HashSet<_>>();
+        // A fresh walk would not have the entries the mapper failed on, nor
+        // with `SkipSubtree` anything below a failed directory.
+        let failed = summary
+            .added
+            .iter()
+            .chain(&summary.modified)
+            .filter(|p| !mapped.contains(*p))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let pruned = match builder.map_error_action {
+            MapErrorAction::SkipSubtree => failed.intersection(&changed_dirs).cloned().collect(),
+            _ => HashSet::new(),
+        };
+        let left_out =
+            |path: &Path| failed.contains(path) || path.ancestors().any(|a| pruned.contains(a));
+        let dropped = self
+            .map
+            .left_values()
+            .filter(|path| left_out(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in &dropped {
+            parents.extend(self.remove_node(path, builder.orphans));
+        }
+        summary.added.retain(|path| !left_out(path));
+        summary.modified.retain(|path| !left_out(path));
+        summary.removed.extend(dropped);
+        let mut added = Vec::new();
+        for (path, data) in nodes {
+            match self.map.get_by_left(&path) {
+                Some(node_id) => *self.arena[*node_id].get_mut() = data,
+                None => {
+                    let node_id = self.arena.new_node(data);
+                    self.map.insert(path.clone(), node_id);
+                    added.push((path, node_id));
+                }
+            }
+        }
+        let orphans = builder.orphans;
+        for (path, node_id) in added {
+            self.attach(&path, node_id, orphans);
+            parents.extend(self.arena[node_id].parent());
+        }
+        // New and reattached nodes were appended last; put them where a fresh walk would.
+        parents.sort();
+        parents.dedup();
+        let position = |path: &Path| order.get(path).copied().unwrap_or(usize::MAX);
+        for parent in parents {
+            self.sort_children(parent, |(a, _), (b, _)| position(a).cmp(&position(b)));
+        }
+
+        self.stamps = stamps;
+        self.dirs = dirs;
+        self.errors = errors;
+        Ok(summary)
+    }
+
+    /// Removes the node at `path`, attaching its children as `orphans` says
+    /// since they have lost their parent directory. Returns the nodes they
+    /// were appended to.
+    pub(crate) fn remove_node(&mut self, path: &Path, orphans: OrphanPolicy) -> Vec<NodeId> {
+        let Some((_, node_id)) = self.map.remove_by_left(path) else {
+            return Vec::new();
+        };
+        self.dirs.remove(path);
+        if node_id.is_removed(&self.arena) {
+            return Vec::new();
+        }
+        let children = node_id.children(&self.arena).collect::<Vec<_>>();
+        for child in &children {
+            child.detach(&mut self.arena);
+        }
+        node_id.remove(&mut self.arena);
+        let mut parents = Vec::new();
+        for child in children {
+            if let Some(key) = self.map.get_by_right(&child).cloned() {
+                self.attach(&key, child, orphans);
+                parents.extend(self.arena[child].parent());
+            }
+        }
+        parents
+    }
+}
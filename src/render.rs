@@ -0,0 +1,196 @@
+use crate::WalkTree;
+use indextree::NodeId;
+use std::{fmt, path::Path};
+
+/// Line drawing characters used by `Render`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Charset {
+    #[default]
+    Unicode,
+    Ascii,
+}
+
+impl Charset {
+    /// Prefixes for a child, a last child, a continued level and a finished level.
+    fn parts(self) -> [&'static str; 4] {
+        match self {
+            Charset::Unicode => ["├── ", "└── ", "│   ", "    "],
+            Charset::Ascii => ["|-- ", "`-- ", "|   ", "    "],
+        }
+    }
+}
+
+type LabelFn<'a, T> = Box<dyn Fn(&Path, &T) -> String + 'a>;
+type IsDirFn<'a, T> = Box<dyn Fn(&Path, &T) -> bool + 'a>;
+
+/// `tree`-style rendering of a `WalkTree`, created by `WalkTree::render`.
+///
+/// Formats through `Display`, for example with `to_string()`:
+///
+/// ```text
+/// src
+/// ├── lib.rs
+/// └── bin
+///     └── main.rs
+///
+/// 1 directory, 2 files
+/// ```
+pub struct Render<'a, T> {
+    tree: &'a WalkTree<T>,
+    max_depth: usize,
+    charset: Charset,
+    dirs_first: bool,
+    summary: bool,
+    label: LabelFn<'a, T>,
+    is_dir: IsDirFn<'a, T>,
+}
+
+impl<T> WalkTree<T> {
+    /// Renders the tree like the `tree` command, labelling nodes with their file name.
+    ///
+    /// Entries that were directories when walked count as directories, as
+    /// do nodes with children. A tree that was deserialized knows only the
+    /// latter, so an empty directory in it needs `Render::with_is_dir` to
+    /// count as one.
+    pub fn render(&self) -> Render<'_, T> {
+        let root = self.root();
+        Render {
+            tree: self,
+            max_depth: usize::MAX,
+            charset: Charset::default(),
+            dirs_first: false,
+            summary: true,
+            label: Box::new(move |path, _| {
+                path.file_name()
+                    .or(root.file_name())
+                    .unwrap_or(root.as_os_str())
+                    .to_string_lossy()
+                    .into_owned()
+            }),
+            is_dir: Box::new(|path, _| {
+                self.dirs.contains(path)
+                    || self
+                        .map
+                        .get_by_left(path)
+                        .is_some_and(|id| self.arena[*id].first_child().is_some())
+            }),
+        }
+    }
+}
+
+impl<'a, T> Render<'a, T> {
+    /// Leaves out nodes more than `depth` levels below a root.
+    pub fn with_max_depth(self, depth: usize) -> Self {
+        Render {
+            max_depth: depth,
+            ..self
+        }
+    }
+    pub fn with_charset(self, charset: Charset) -> Self {
+        Render { charset, ..self }
+    }
+    /// Lists the directories among a node's children before its files.
+    pub fn with_dirs_first(self) -> Self {
+        Render {
+            dirs_first: true,
+            ..self
+        }
+    }
+    /// Whether to end with the "N directories, M files" line, on by default.
+    pub fn with_summary(self, summary: bool) -> Self {
+        Render { summary, ..self }
+    }
+    /// Labels each node with `f`, which receives its key in `WalkTree::map`,
+    /// instead of its file name.
+    pub fn with_label<F>(self, f: F) -> Self
+    where
+        F: Fn(&Path, &T) -> String + 'a,
+    {
+        Render {
+            label: Box::new(f),
+            ..self
+        }
+    }
+    /// Tells directories from files with `f` instead of by their kind and
+    /// children, for example with `|_, e| e.file_type.is_dir()` on an `EntryInfo` tree.
+    pub fn with_is_dir<F>(self, f: F) -> Self
+    where
+        F: Fn(&Path, &T) -> bool + 'a,
+    {
+        Render {
+            is_dir: Box::new(f),
+            ..self
+        }
+    }
+
+    fn is_dir(&self, node_id: NodeId) -> bool {
+        let path = self.tree.get_path_by_node_id(node_id).unwrap();
+        (self.is_dir)(path, self.tree.arena[node_id].get())
+    }
+
+    fn label(&self, node_id: NodeId) -> String {
+        let path = self.tree.get_path_by_node_id(node_id).unwrap();
+        (self.label)(path, self.tree.arena[node_id].get())
+    }
+
+    fn write_children(
+        &self,
+        f: &mut fmt::Formatter,
+        node_id: NodeId,
+        depth: usize,
+        prefix: &mut String,
+        counts: &mut (usize, usize),
+    ) -> fmt::Result {
+        if depth >= self.max_depth {
+            return Ok(());
+        }
+        let [child, last_child, more, done] = self.charset.parts();
+        let mut children = node_id.children(&self.tree.arena).collect::<Vec<_>>();
+        if self.dirs_first {
+            children.sort_by_cached_key(|id| !self.is_dir(*id));
+        }
+        for (index, id) in children.iter().enumerate() {
+            let last = index + 1 == children.len();
+            let label = self.label(*id);
+            writeln!(
+                f,
+                "{}{}{}",
+                prefix,
+                if last { last_child } else { child },
+                label
+            )?;
+            if self.is_dir(*id) {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+            let len = prefix.len();
+            prefix.push_str(if last { done } else { more });
+            self.write_children(f, *id, depth + 1, prefix, counts)?;
+            prefix.truncate(len);
+        }
+        Ok(())
+    }
+}
+
+impl<T> fmt::Display for Render<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut counts = (0, 0);
+        for root in self.tree.roots() {
+            writeln!(f, "{}", self.label(root))?;
+            self.write_children(f, root, 0, &mut String::new(), &mut counts)?;
+        }
+        if self.summary {
+            let (dirs, files) = counts;
+            writeln!(
+                f,
+                "\n{} director{}, {} file{}",
+                dirs,
+                if dirs == 1 { "y" } else { "ies" },
+                files,
+                if files == 1 { "" } else { "s" }
+            )?;
+        }
+        Ok(())
+    }
+}
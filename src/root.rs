@@ -0,0 +1,124 @@
+use crate::{FileSystem, WalkTreeError};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
+
+/// Expands `~`, `~user`, `$VAR` and `${VAR}` in `root`, then canonicalizes it
+/// and checks that it is a directory.
+pub(crate) fn resolve(root: &Path, fs: &dyn FileSystem) -> Result<PathBuf, WalkTreeError> {
+    let invalid = |reason: String| WalkTreeError::InvalidRoot {
+        path: root.to_path_buf(),
+        reason,
+    };
+    // Paths that are not valid UTF-8 cannot contain anything to expand that we could read.
+    let expanded = match root.to_str() {
+        Some(root) => PathBuf::from(expand_vars(&expand_tilde(root)?)?),
+        None => root.to_path_buf(),
+    };
+    let canonical = fs.canonicalize(&expanded).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => invalid(format!("{} does not exist", expanded.display())),
+        _ => invalid(err.to_string()),
+    })?;
+    if !fs.metadata(&canonical).is_ok_and(|m| m.is_dir()) {
+        return Err(invalid(format!(
+            "{} is not a directory",
+            canonical.display()
+        )));
+    }
+    Ok(canonical)
+}
+
+fn expand_tilde(root: &str) -> Result<String, WalkTreeError> {
+    let Some(rest) = root.strip_prefix('~') else {
+        return Ok(root.to_string());
+    };
+    let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let home = if user.is_empty() {
+        env::home_dir()
+    } else {
+        user_home(user)
+    };
+    let home = home.ok_or_else(|| WalkTreeError::InvalidRoot {
+        path: PathBuf::from(root),
+        reason: match user {
+            "" => "the home directory is unknown".to_string(),
+            user => format!("unknown user {:?}", user),
+        },
+    })?;
+    Ok(format!("{}{}", home.display(), rest))
+}
+
+fn expand_vars(root: &str) -> Result<String, WalkTreeError> {
+    let mut expanded = String::with_capacity(root.len());
+    let mut rest = root;
+    while let Some(dollar) = rest.find('$') {
+        expanded.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let value = env::var(name).map_err(|err| WalkTreeError::InvalidRoot {
+            path: PathBuf::from(root),
+            reason: format!("${}: {}", name, err),
+        })?;
+        expanded.push_str(&value);
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(unix)]
+fn user_home(user: &str) -> Option<PathBuf> {
+    use std::{
+        ffi::{CStr, CString, OsStr},
+        os::unix::ffi::OsStrExt,
+    };
+    let name = CString::new(user).ok()?;
+    let mut buffer = vec![0; 1024];
+    loop {
+        let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+        let mut result = std::ptr::null_mut();
+        // SAFETY: every pointer is valid for the duration of the call and
+        // `buffer.len()` is the size of `buffer`.
+        let code = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if code == libc::ERANGE && buffer.len() < 1 << 20 {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if code != 0 || result.is_null() || passwd.pw_dir.is_null() {
+            return None;
+        }
+        // SAFETY: `pw_dir` points into `buffer`, which is still alive.
+        let dir = unsafe { CStr::from_ptr(passwd.pw_dir) };
+        return Some(PathBuf::from(OsStr::from_bytes(dir.to_bytes())));
+    }
+}
+
+#[cfg(not(unix))]
+fn user_home(_user: &str) -> Option<PathBuf> {
+    None
+}
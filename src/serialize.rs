@@ -0,0 +1,374 @@
+use crate::{WalkTree, WalkTreeError};
+use bimap::BiMap;
+use indextree::{Arena, NodeId};
+use serde::{
+    de::{Error as _, MapAccess, Visitor},
+    ser::{SerializeSeq, SerializeStruct, SerializeTuple},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    marker::PhantomData,
+    path::PathBuf,
+};
+
+/// A node read back in pre-order: its path, the position of its parent in
+/// pre-order, its position in `arena` if it was recorded, and its item.
+type Loaded<T> = (PathBuf, Option<usize>, Option<usize>, T);
+
+impl<T> WalkTree<T> {
+    /// Builds a tree from nodes in pre-order. The nodes are allocated in the
+    /// order of their recorded positions and linked in pre-order, so both the
+    /// `NodeId`s and the order of siblings come back.
+    fn from_pre_order(root: PathBuf, nodes: Vec<Loaded<T>>) -> Result<Self, String> {
+        let mut order = (0..nodes.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| nodes[i].2.unwrap_or(i));
+        if let Some((position, _)) = order
+            .iter()
+            .enumerate()
+            .find(|(position, &i)| nodes[i].2.unwrap_or(i) != *position)
+        {
+            return Err(format!("no node or several nodes at position {}", position));
+        }
+        let mut items = Vec::with_capacity(nodes.len());
+        let mut links = Vec::with_capacity(nodes.len());
+        for (path, parent, _, data) in nodes {
+            items.push(Some(data));
+            links.push((path, parent));
+        }
+        let mut arena = Arena::new();
+        let mut ids = vec![None; items.len()];
+        for i in order {
+            ids[i] = items[i].take().map(|data| arena.new_node(data));
+        }
+        let ids = ids.into_iter().flatten().collect::<Vec<_>>();
+        let mut map = BiMap::new();
+        for (i, (path, parent)) in links.into_iter().enumerate() {
+            match parent {
+                Some(parent) if parent < i => ids[parent].append(ids[i], &mut arena),
+                Some(_) => {
+                    return Err(format!("the parent of {} is not before it", path.display()))
+                }
+                None => {}
+            }
+            if map.contains_left(&path) {
+                return Err(format!("several nodes at {}", path.display()));
+            }
+            map.insert(path, ids[i]);
+        }
+        Ok(WalkTree {
+            arena,
+            map,
+            errors: Vec::new(),
+            root,
+            stamps: HashMap::new(),
+            dirs: HashSet::new(),
+            loaded_many: false,
+        })
+    }
+
+    /// The position of every node in `arena`, not counting removed ones.
+    fn positions(&self) -> HashMap<NodeId, usize> {
+        self.node_ids()
+            .into_iter()
+            .enumerate()
+            .map(|(position, node_id)| (node_id, position))
+            .collect()
+    }
+
+    fn path(&self, node_id: NodeId) -> &PathBuf {
+        self.map.get_by_right(&node_id).unwrap()
+    }
+
+    /// Encodes the tree in the `compact` form with postcard.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, WalkTreeError>
+    where
+        T: Serialize,
+    {
+        postcard::to_allocvec(&Compact(self)).map_err(|err| WalkTreeError::Encoding(err.into()))
+    }
+
+    /// Decodes a tree written by `to_bytes`.
+    pub fn from_bytes<'de>(bytes: &'de [u8]) -> Result<Self, WalkTreeError>
+    where
+        T: Deserialize<'de>,
+    {
+        let compact = postcard::from_bytes::<CompactTree<T>>(bytes)
+            .map_err(|err| WalkTreeError::Encoding(err.into()))?;
+        WalkTree::from_compact(compact).map_err(|err| WalkTreeError::Encoding(err.into()))
+    }
+}
+
+struct Node<'a, T> {
+    tree: &'a WalkTree<T>,
+    positions: &'a HashMap<NodeId, usize>,
+    node_id: NodeId,
+}
+
+// Derived, these would require `T: Copy`.
+impl<T> Clone for Node<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Node<'_, T> {}
+
+struct Children<'a, T>(Node<'a, T>);
+
+impl<T: Serialize> Serialize for Node<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut node = serializer.serialize_struct("Node", 4)?;
+        node.serialize_field("id", &self.positions[&self.node_id])?;
+        node.serialize_field("path", self.tree.path(self.node_id))?;
+        node.serialize_field("data", self.tree.arena[self.node_id].get())?;
+        node.serialize_field("children", &Children(*self))?;
+        node.end()
+    }
+}
+
+impl<T: Serialize> Serialize for Children<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node = self.0;
+        serializer.collect_seq(
+            node.node_id
+                .children(&node.tree.arena)
+                .map(|node_id| Node { node_id, ..node }),
+        )
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Node")]
+struct OwnedNode<T> {
+    #[serde(default)]
+    id: Option<usize>,
+    path: PathBuf,
+    data: T,
+    children: Vec<OwnedNode<T>>,
+}
+
+struct Roots<'a, T>(&'a WalkTree<T>);
+
+impl<T: Serialize> Serialize for Roots<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tree = self.0;
+        let positions = &tree.positions();
+        serializer.collect_seq(tree.roots().into_iter().map(|node_id| Node {
+            tree,
+            positions,
+            node_id,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "WalkTree")]
+struct OwnedTree<N> {
+    root: PathBuf,
+    nodes: N,
+}
+
+/// `{"root": ..., "nodes": [{"id": ..., "path": ..., "data": ..., "children": [...]}, ...]}`,
+/// with one element in `nodes` per root and paths relative to `root`.
+///
+/// This and the `compact` form record the position of each node in `arena`,
+/// so a loaded tree has the same `NodeId`s and the same order of siblings.
+/// Removing nodes leaves gaps in `arena`, which a loaded tree does not have;
+/// its `NodeId`s then follow the same order. `errors` is not serialized.
+impl<T: Serialize> Serialize for WalkTree<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tree = serializer.serialize_struct("WalkTree", 2)?;
+        tree.serialize_field("root", &self.root)?;
+        tree.serialize_field("nodes", &Roots(self))?;
+        tree.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for WalkTree<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tree = OwnedTree::<Vec<OwnedNode<T>>>::deserialize(deserializer)?;
+        let mut nodes = Vec::new();
+        let mut stack = tree
+            .nodes
+            .into_iter()
+            .rev()
+            .map(|root| (None, root))
+            .collect::<Vec<_>>();
+        while let Some((parent, node)) = stack.pop() {
+            let index = nodes.len();
+            stack.extend(node.children.into_iter().rev().map(|c| (Some(index), c)));
+            nodes.push((node.path, parent, node.id, node.data));
+        }
+        WalkTree::from_pre_order(tree.root, nodes).map_err(D::Error::custom)
+    }
+}
+
+/// The nested form `WalkTree` serializes as by default.
+pub mod nested {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        tree: &WalkTree<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        tree.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<WalkTree<T>, D::Error> {
+        WalkTree::deserialize(deserializer)
+    }
+}
+
+/// `{"root": ..., "nodes": {path: item, ...}}`, a map from each path
+/// relative to `root` to its item. The structure follows from the paths.
+///
+/// Only the paths and items are kept: nodes get their `NodeId`s and are
+/// linked below their parents in the order the map is read back in, which
+/// formats such as `serde_json::Value` sort by path.
+pub mod flat {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        tree: &WalkTree<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut flat = serializer.serialize_struct("WalkTree", 2)?;
+        flat.serialize_field("root", &tree.root)?;
+        flat.serialize_field("nodes", &Nodes(tree))?;
+        flat.end()
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<WalkTree<T>, D::Error> {
+        let tree = OwnedTree::<Entries<T>>::deserialize(deserializer)?;
+        let mut positions = HashMap::new();
+        let mut nodes = Vec::with_capacity(tree.nodes.0.len());
+        for (index, (path, data)) in tree.nodes.0.into_iter().enumerate() {
+            let parent = path.parent().and_then(|p| positions.get(p).copied());
+            positions.insert(path.clone(), index);
+            nodes.push((path, parent, None, data));
+        }
+        WalkTree::from_pre_order(tree.root, nodes).map_err(D::Error::custom)
+    }
+
+    struct Nodes<'a, T>(&'a WalkTree<T>);
+
+    impl<T: Serialize> Serialize for Nodes<'_, T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let tree = self.0;
+            serializer.collect_map(
+                tree.pre_order()
+                    .map(|(id, ..)| (tree.path(id), tree.arena[id].get())),
+            )
+        }
+    }
+
+    /// Entries in document order, unlike a `HashMap`.
+    struct Entries<T>(Vec<(PathBuf, T)>);
+
+    impl<'de, T: Deserialize<'de>> Deserialize<'de> for Entries<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer
+                .deserialize_map(FlatVisitor(PhantomData))
+                .map(Entries)
+        }
+    }
+
+    struct FlatVisitor<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for FlatVisitor<T> {
+        type Value = Vec<(PathBuf, T)>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a map from paths to items")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+            while let Some(entry) = map.next_entry()? {
+                entries.push(entry);
+            }
+            Ok(entries)
+        }
+    }
+}
+
+/// The root followed by a sequence of `(id, parent, name, item)` tuples in
+/// pre-order, where `id` is the position of the node in `arena`, `parent` the
+/// position of the parent node in the sequence and `name` the path relative
+/// to it. Meant for binary formats, see `WalkTree::to_bytes`.
+pub mod compact {
+    use super::*;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        tree: &WalkTree<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Compact(tree).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<WalkTree<T>, D::Error> {
+        WalkTree::from_compact(CompactTree::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+struct Compact<'a, T>(&'a WalkTree<T>);
+
+type CompactTree<T> = (PathBuf, Vec<(usize, Option<usize>, PathBuf, T)>);
+
+impl<T: Serialize> Serialize for Compact<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut compact = serializer.serialize_tuple(2)?;
+        compact.serialize_element(&self.0.root)?;
+        compact.serialize_element(&CompactNodes(self.0))?;
+        compact.end()
+    }
+}
+
+struct CompactNodes<'a, T>(&'a WalkTree<T>);
+
+impl<T: Serialize> Serialize for CompactNodes<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tree = self.0;
+        let ids = tree.positions();
+        let mut positions = HashMap::new();
+        let mut nodes = serializer.serialize_seq(Some(tree.map.len()))?;
+        for (index, (node_id, ..)) in tree.pre_order().enumerate() {
+            positions.insert(node_id, index);
+            let path = tree.path(node_id);
+            let parent = tree.arena[node_id].parent();
+            let name = match parent {
+                Some(parent) => path.strip_prefix(tree.path(parent)).unwrap_or(path),
+                None => path,
+            };
+            let parent = parent.map(|p| positions[&p]);
+            nodes.serialize_element(&(ids[&node_id], parent, name, tree.arena[node_id].get()))?;
+        }
+        nodes.end()
+    }
+}
+
+impl<T> WalkTree<T> {
+    fn from_compact((root, nodes): CompactTree<T>) -> Result<Self, String> {
+        let mut paths = Vec::<PathBuf>::with_capacity(nodes.len());
+        let nodes = nodes
+            .into_iter()
+            .map(|(id, parent, name, data)| {
+                let path = match parent.and_then(|p| paths.get(p)) {
+                    Some(parent) => parent.join(name),
+                    None => name,
+                };
+                paths.push(path.clone());
+                (path, parent, Some(id), data)
+            })
+            .collect::<Vec<_>>();
+        WalkTree::from_pre_order(root, nodes)
+    }
+}
@@ -0,0 +1,220 @@
+use crate::WalkTree;
+use bimap::BiMap;
+use indextree::{Arena, Node, NodeId};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+impl<T> WalkTree<T> {
+    /// A copy of the subtree at `path`, as if its directory had been walked:
+    /// keys become relative to it and `root()` is the directory. Errors are
+    /// not copied. `None` if there is no node at `path`.
+    pub fn subtree(&self, path: &Path) -> Option<WalkTree<T>>
+    where
+        T: Clone,
+    {
+        let node_id = *self.get_node_id_by_path(path)?;
+        let node_ids = node_id.descendants(&self.arena).collect::<Vec<_>>();
+        let data = node_ids
+            .iter()
+            .map(|id| (*id, self.arena[*id].get().clone()))
+            .collect();
+        Some(
+            self.with_data(node_ids, data)
+                .rebase(node_id_key(self, node_id)),
+        )
+    }
+    /// Like `subtree`, but moves the nodes out of this tree instead of
+    /// copying them, and keeps the errors of entries below `path`.
+    pub fn into_subtree(self, path: &Path) -> Option<WalkTree<T>> {
+        self.into_subtrees([path]).pop().flatten()
+    }
+    /// Splits the tree into the subtrees at `paths`, for example one per
+    /// package of a workspace, moving the nodes and errors below each of
+    /// them. A subtree leaves out the ones at paths below its own, and nodes
+    /// outside of all of them are dropped. A path that is not in the tree,
+    /// or was given before, gets `None`.
+    pub fn into_subtrees<I, P>(mut self, paths: I) -> Vec<Option<WalkTree<T>>>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut tops = HashMap::new();
+        let keys = paths
+            .into_iter()
+            .enumerate()
+            .map(|(index, path)| {
+                let node_id = *self.get_node_id_by_path(path.as_ref())?;
+                if tops.contains_key(&node_id) {
+                    return None;
+                }
+                tops.insert(node_id, index);
+                Some(node_id_key(&self, node_id))
+            })
+            .collect::<Vec<_>>();
+
+        // Pre-order visits a parent before its children, which it passes its
+        // subtree on to.
+        let mut owners = HashMap::new();
+        let mut nodes = vec![Vec::new(); keys.len()];
+        for root in self.roots() {
+            for node_id in root.descendants(&self.arena) {
+                let parent = self.arena[node_id].parent();
+                let owner = tops.get(&node_id).or_else(|| owners.get(&parent?));
+                let Some(&owner) = owner else {
+                    continue;
+                };
+                owners.insert(node_id, owner);
+                let parent = parent.filter(|parent| owners.get(parent) == Some(&owner));
+                nodes[owner].push((node_id, parent));
+            }
+        }
+        let error_owners = self
+            .errors
+            .iter()
+            .map(|err| {
+                let key = self.error_key(err.path()?)?;
+                let node_id = key.ancestors().find_map(|dir| self.map.get_by_left(dir))?;
+                owners.get(node_id).copied()
+            })
+            .collect::<Vec<_>>();
+        let mut errors = keys.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+        for (err, owner) in std::mem::take(&mut self.errors)
+            .into_iter()
+            .zip(error_owners)
+        {
+            if let Some(owner) = owner {
+                errors[owner].push(err);
+            }
+        }
+
+        let mut data = std::mem::take(&mut self.arena)
+            .into_iter()
+            .map(Node::into_data)
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .zip(nodes)
+            .zip(errors)
+            .map(|((key, nodes), errors)| {
+                let key = key?;
+                let mut arena = Arena::new();
+                let mut map = BiMap::new();
+                let mut new_ids = HashMap::<NodeId, NodeId>::new();
+                for (node_id, parent) in nodes {
+                    let data = data[usize::from(node_id) - 1].take().unwrap();
+                    let new_id = arena.new_node(data);
+                    if let Some(parent) = parent {
+                        new_ids[&parent].append(new_id, &mut arena);
+                    }
+                    new_ids.insert(node_id, new_id);
+                    if let Some(path) = self.map.get_by_right(&node_id) {
+                        map.insert(path.clone(), new_id);
+                    }
+                }
+                let stamps = self
+                    .stamps
+                    .iter()
+                    .filter(|(path, _)| map.contains_left(*path))
+                    .map(|(path, stamp)| (path.clone(), *stamp))
+                    .collect();
+                let dirs = self
+                    .dirs
+                    .iter()
+                    .filter(|path| map.contains_left(*path))
+                    .cloned()
+                    .collect();
+                let tree = WalkTree {
+                    arena,
+                    map,
+                    errors,
+                    root: self.root.clone(),
+                    stamps,
+                    dirs,
+                    loaded_many: false,
+                };
+                Some(tree.rebase(key))
+            })
+            .collect()
+    }
+    /// Removes the node at `path` and everything below it, returning their keys.
+    pub fn prune(&mut self, path: &Path) -> Vec<PathBuf> {
+        match self.get_node_id_by_path(path) {
+            Some(node_id) => self.prune_node(*node_id),
+            None => Vec::new(),
+        }
+    }
+    /// Keeps only the nodes for which `f` returns `true`. A node that is not
+    /// kept is removed with everything below it, without calling `f` on it.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Path, &T) -> bool,
+    {
+        let mut pruned = Vec::new();
+        let mut pruned_depth = None;
+        for (node_id, path, data, depth) in self.pre_order() {
+            // Pre-order lists the nodes below a pruned one right after it.
+            if pruned_depth.is_some_and(|d| depth > d) {
+                continue;
+            }
+            pruned_depth = None;
+            if !f(path, data) {
+                pruned.push(node_id);
+                pruned_depth = Some(depth);
+            }
+        }
+        for node_id in pruned {
+            self.prune_node(node_id);
+        }
+    }
+
+    fn prune_node(&mut self, node_id: NodeId) -> Vec<PathBuf> {
+        let keys = node_id
+            .descendants(&self.arena)
+            .filter_map(|id| self.map.remove_by_right(&id))
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.stamps.remove(key);
+            self.dirs.remove(key);
+        }
+        node_id.remove_subtree(&mut self.arena);
+        keys
+    }
+
+    /// The key of an error's `path`, which is as walked: relative to the
+    /// working directory, or through a symlink to the root. The nearest
+    /// directory of it in the tree gives the key.
+    fn error_key(&self, path: &Path) -> Option<PathBuf> {
+        let path = std::path::absolute(path).ok()?;
+        path.ancestors().find_map(|dir| {
+            let node_id = self.get_node_id_by_path(dir)?;
+            let key = self.map.get_by_right(node_id)?;
+            Some(key.join(path.strip_prefix(dir).ok()?))
+        })
+    }
+
+    /// Makes the keys relative to `key`, whose node becomes the root.
+    fn rebase(mut self, key: PathBuf) -> Self {
+        let rebased = |path: &Path| path.strip_prefix(&key).ok().map(Path::to_path_buf);
+        self.map = self
+            .map
+            .iter()
+            .filter_map(|(path, id)| Some((rebased(path)?, *id)))
+            .collect();
+        self.stamps = self
+            .stamps
+            .iter()
+            .filter_map(|(path, stamp)| Some((rebased(path)?, *stamp)))
+            .collect();
+        self.dirs = self.dirs.iter().filter_map(|path| rebased(path)).collect();
+        if !key.as_os_str().is_empty() {
+            self.root = self.root.join(&key);
+        }
+        self
+    }
+}
+
+fn node_id_key<T>(tree: &WalkTree<T>, node_id: NodeId) -> PathBuf {
+    tree.map.get_by_right(&node_id).unwrap().clone()
+}
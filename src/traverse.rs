@@ -0,0 +1,116 @@
+use crate::WalkTree;
+use indextree::{NodeEdge, NodeId};
+use std::{collections::VecDeque, iter, path::Path};
+
+/// A node yielded by the traversals of `WalkTree`: its id, its key in
+/// `WalkTree::map`, its item and its depth below the root it belongs to.
+pub type Visit<'a, T> = (NodeId, &'a Path, &'a T, usize);
+
+impl<T> WalkTree<T> {
+    /// Every node, each before its children.
+    pub fn pre_order(&self) -> impl Iterator<Item = Visit<'_, T>> {
+        self.roots()
+            .into_iter()
+            .flat_map(move |root| self.depth_first(root, 0, false))
+    }
+    /// Every node, each after its children.
+    pub fn post_order(&self) -> impl Iterator<Item = Visit<'_, T>> {
+        self.roots()
+            .into_iter()
+            .flat_map(move |root| self.depth_first(root, 0, true))
+    }
+    /// Every node, level by level.
+    pub fn breadth_first(&self) -> impl Iterator<Item = Visit<'_, T>> {
+        let mut queue = self
+            .roots()
+            .into_iter()
+            .map(|root| (root, 0))
+            .collect::<VecDeque<_>>();
+        iter::from_fn(move || {
+            let (node_id, depth) = queue.pop_front()?;
+            queue.extend(node_id.children(&self.arena).map(|c| (c, depth + 1)));
+            Some(self.visit(node_id, depth))
+        })
+    }
+    /// The nodes without children, in pre-order.
+    pub fn leaves(&self) -> impl Iterator<Item = Visit<'_, T>> {
+        self.pre_order()
+            .filter(|(node_id, ..)| self.arena[*node_id].first_child().is_none())
+    }
+    /// The children of the node at `path`, looked up like `get_node_id_by_path`.
+    pub fn children(&self, path: &Path) -> impl Iterator<Item = Visit<'_, T>> {
+        self.lookup(path)
+            .into_iter()
+            .flat_map(move |(node_id, depth)| {
+                node_id
+                    .children(&self.arena)
+                    .map(move |child| self.visit(child, depth + 1))
+            })
+    }
+    /// The ancestors of the node at `path`, nearest first.
+    pub fn ancestors(&self, path: &Path) -> impl Iterator<Item = Visit<'_, T>> {
+        self.lookup(path)
+            .into_iter()
+            .flat_map(move |(node_id, depth)| {
+                node_id
+                    .ancestors(&self.arena)
+                    .skip(1)
+                    .zip((0..depth).rev())
+                    .map(|(ancestor, depth)| self.visit(ancestor, depth))
+            })
+    }
+    /// The other children of the parent of the node at `path`, or the other
+    /// roots if it is a root, in order.
+    pub fn siblings(&self, path: &Path) -> impl Iterator<Item = Visit<'_, T>> {
+        self.lookup(path)
+            .into_iter()
+            .flat_map(move |(node_id, depth)| {
+                let siblings = match self.arena[node_id].parent() {
+                    Some(parent) => parent.children(&self.arena).collect(),
+                    None => self.roots(),
+                };
+                siblings
+                    .into_iter()
+                    .filter(move |sibling| *sibling != node_id)
+                    .map(move |sibling| self.visit(sibling, depth))
+            })
+    }
+    /// The nodes below the one at `path`, in pre-order.
+    pub fn descendants(&self, path: &Path) -> impl Iterator<Item = Visit<'_, T>> {
+        self.lookup(path)
+            .into_iter()
+            .flat_map(move |(node_id, depth)| self.depth_first(node_id, depth, false).skip(1))
+    }
+
+    /// The node at `path` and its depth.
+    fn lookup(&self, path: &Path) -> Option<(NodeId, usize)> {
+        let node_id = *self.get_node_id_by_path(path)?;
+        Some((node_id, node_id.ancestors(&self.arena).count() - 1))
+    }
+
+    fn visit(&self, node_id: NodeId, depth: usize) -> Visit<'_, T> {
+        let path = self.map.get_by_right(&node_id).unwrap();
+        (node_id, path, self.arena[node_id].get(), depth)
+    }
+
+    /// The subtree of `root`, which lies `depth` levels below its own root.
+    fn depth_first(
+        &self,
+        root: NodeId,
+        depth: usize,
+        post_order: bool,
+    ) -> impl Iterator<Item = Visit<'_, T>> {
+        let mut level = depth;
+        root.traverse(&self.arena)
+            .filter_map(move |edge| match edge {
+                NodeEdge::Start(node_id) => {
+                    level += 1;
+                    (!post_order).then(|| self.visit(node_id, level - 1))
+                }
+                NodeEdge::End(node_id) => {
+                    level -= 1;
+                    post_order.then(|| self.visit(node_id, level))
+                }
+            })
+    }
+}
@@ -0,0 +1,425 @@
+use crate::{
+    entry::Stamp, DirEntry, EntryFilter, FileKind, MapErrorAction, WalkTree, WalkTreeBuilder,
+    WalkTreeError,
+};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use rayon::ThreadPool;
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, Sender},
+};
+use walkdir::WalkDir;
+
+/// A change applied to a watched tree, with paths relative to its root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Created(PathBuf),
+    Removed(PathBuf),
+    Modified(PathBuf),
+    /// A node and its subtree moved within the tree.
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+/// A `WalkTree` kept in sync with the filesystem through inotify.
+///
+/// Created with `WalkTreeBuilder::watch`. Events are applied when `poll` or
+/// `wait` is called, using the builder's filter, mapper and error handling;
+/// mapping errors end up in the tree's `errors`. New nodes are appended after
+/// their existing siblings, and symlinks are never followed.
+pub struct Watcher<T> {
+    tree: WalkTree<T>,
+    builder: WalkTreeBuilder<T>,
+    filter: EntryFilter,
+    pool: Option<ThreadPool>,
+    max_depth: usize,
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    watches: HashMap<PathBuf, WatchDescriptor>,
+    subscribers: Vec<Sender<ChangeEvent>>,
+    buffer: Vec<u8>,
+}
+
+struct RawEvent {
+    wd: WatchDescriptor,
+    mask: EventMask,
+    cookie: u32,
+    name: Option<OsString>,
+}
+
+impl<T> WalkTreeBuilder<T> {
+    /// Walks the tree and starts watching every directory in it for changes.
+    pub fn watch(mut self) -> Result<Watcher<T>, WalkTreeError> {
+        if self.fs.is_some() {
+            return Err(WalkTreeError::InvalidOptions(
+                "only the disk can be watched, not a custom file system".to_string(),
+            ));
+        }
+        let pool = self.prepare()?;
+        let max_depth = self.walk_config()?.max_depth;
+        // Events on entries whose stamp did not change are dropped.
+        self.stamps = true;
+        let mut filter = self.compose_filter()?;
+        let tree = self.walk_with(pool.as_ref(), &mut filter)?;
+        let inotify = Inotify::init().map_err(|source| WalkTreeError::Io {
+            path: None,
+            depth: 0,
+            source,
+        })?;
+        let mut watcher = Watcher {
+            tree,
+            builder: self,
+            filter,
+            pool,
+            max_depth,
+            inotify,
+            dirs: HashMap::new(),
+            watches: HashMap::new(),
+            subscribers: Vec::new(),
+            buffer: vec![0; 4096],
+        };
+        let paths = watcher.tree.map.left_values().cloned().collect::<Vec<_>>();
+        for path in paths {
+            watcher.add_watch(&watcher.fs_path(&path))?;
+        }
+        Ok(watcher)
+    }
+}
+
+impl<T> Watcher<T> {
+    pub fn tree(&self) -> &WalkTree<T> {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> WalkTree<T> {
+        self.tree
+    }
+
+    /// Returns a channel receiving every event applied from now on.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Applies pending filesystem events without blocking.
+    pub fn poll(&mut self) -> Result<Vec<ChangeEvent>, WalkTreeError> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let events = match self.inotify.read_events(&mut buffer) {
+            Ok(events) => Ok(events.map(RawEvent::from).collect()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Vec::new()),
+            Err(err) => Err(err),
+        };
+        self.buffer = buffer;
+        self.apply(events.map_err(|source| self.io_error(source))?)
+    }
+
+    /// Blocks until at least one filesystem event arrived, then applies it.
+    pub fn wait(&mut self) -> Result<Vec<ChangeEvent>, WalkTreeError> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        let events = self
+            .inotify
+            .read_events_blocking(&mut buffer)
+            .map(|events| events.map(RawEvent::from).collect());
+        self.buffer = buffer;
+        self.apply(events.map_err(|source| self.io_error(source))?)
+    }
+
+    fn apply(&mut self, events: Vec<RawEvent>) -> Result<Vec<ChangeEvent>, WalkTreeError> {
+        let mut changes = Vec::new();
+        let mut moved_from = HashMap::new();
+        let mut modified = Vec::new();
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                self.resync(&mut changes)?;
+                continue;
+            }
+            let (Some(dir), Some(name)) = (self.dirs.get(&event.wd), event.name) else {
+                continue;
+            };
+            let path = dir.join(name);
+            if event.mask.contains(EventMask::CREATE) {
+                let created = self.insert(&path)?;
+                changes.extend(created.into_iter().map(ChangeEvent::Created));
+            } else if event.mask.contains(EventMask::MOVED_FROM) {
+                moved_from.insert(event.cookie, path);
+            } else if event.mask.contains(EventMask::MOVED_TO) {
+                let from = moved_from.remove(&event.cookie);
+                let removed = from.as_deref().map(|from| self.remove(from));
+                let removed = removed.unwrap_or_default();
+                let created = self.insert(&path)?;
+                match from {
+                    Some(from) if !removed.is_empty() && !created.is_empty() => {
+                        changes.push(ChangeEvent::Renamed {
+                            from: self.builder.key(&from),
+                            to: self.builder.key(&path),
+                        })
+                    }
+                    _ => {
+                        changes.extend(removed.into_iter().map(ChangeEvent::Removed));
+                        changes.extend(created.into_iter().map(ChangeEvent::Created));
+                    }
+                }
+            } else if event.mask.contains(EventMask::DELETE) {
+                let removed = self.remove(&path);
+                changes.extend(removed.into_iter().map(ChangeEvent::Removed));
+            } else if event.mask.intersects(EventMask::MODIFY | EventMask::ATTRIB)
+                && !modified.contains(&path)
+            {
+                modified.push(path);
+            }
+        }
+        // Moved out of the watched tree.
+        for from in moved_from.into_values() {
+            let removed = self.remove(&from);
+            changes.extend(removed.into_iter().map(ChangeEvent::Removed));
+        }
+        for path in modified {
+            changes.extend(self.update(&path)?);
+        }
+        self.subscribers
+            .retain(|s| changes.iter().all(|c| s.send(c.clone()).is_ok()));
+        Ok(changes)
+    }
+
+    /// Walks `path` and adds it and its descendants, returning their keys.
+    fn insert(&mut self, path: &Path) -> Result<Vec<PathBuf>, WalkTreeError> {
+        let depth = match self.depth(path) {
+            Some(depth) if depth <= self.max_depth => depth,
+            _ => return Ok(Vec::new()),
+        };
+        if path
+            .parent()
+            .is_none_or(|p| !self.tree.map.contains_left(&self.builder.key(p)))
+        {
+            return Ok(Vec::new());
+        }
+        let filter = &mut self.filter;
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        let iter = WalkDir::new(path)
+            .max_depth(self.max_depth - depth)
+            .into_iter()
+            .filter_entry(|e| {
+                let entry = DirEntry::new(
+                    e.path().to_path_buf(),
+                    e.file_type().into(),
+                    depth + e.depth(),
+                    false,
+                    None,
+                );
+                filter.accepts(&entry)
+            });
+        for result in iter {
+            match result {
+                Ok(e) => {
+                    let file_type = FileKind::from(e.file_type());
+                    let depth = depth + e.depth();
+                    entries.push(DirEntry::new(e.into_path(), file_type, depth, false, None));
+                }
+                // Deleted or moved again before the event was read.
+                Err(err)
+                    if err.depth() == 0
+                        && err
+                            .io_error()
+                            .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => {}
+                Err(err) => errors.push(WalkTreeError::from(err)),
+            }
+        }
+        errors.extend(self.filter.take_errors());
+        for err in errors {
+            self.builder
+                .error_policy
+                .handle(err, &mut self.tree.errors)?;
+        }
+        entries.retain(|e| !self.tree.map.contains_left(&self.builder.key(e.path())));
+        let dirs = self.builder.dirs_of(&entries);
+        self.tree.dirs.extend(dirs);
+        for entry in &entries {
+            if let Some(stamp) = Stamp::of(entry) {
+                self.tree
+                    .stamps
+                    .insert(self.builder.key(entry.path()), stamp);
+            }
+        }
+
+        let nodes =
+            self.builder
+                .apply_map_fn(entries, self.pool.as_ref(), &mut self.tree.errors)?;
+        let mut created = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let key = self.builder.key(&node.path);
+            let node_id = self.tree.arena.new_node(node.data);
+            let orphans = self.builder.orphans;
+            self.tree.attach(&key, node_id, orphans);
+            self.tree.map.insert(key.clone(), node_id);
+            self.add_watch(&node.path)?;
+            created.push(key);
+        }
+        Ok(created)
+    }
+
+    /// Removes `path` and its descendants, returning their keys.
+    fn remove(&mut self, path: &Path) -> Vec<PathBuf> {
+        let Some(node_id) = self.tree.map.get_by_left(&self.builder.key(path)) else {
+            return Vec::new();
+        };
+        let removed = node_id
+            .descendants(&self.tree.arena)
+            .filter_map(|id| self.tree.map.get_by_right(&id).cloned())
+            .collect::<Vec<_>>();
+        for key in &removed {
+            self.tree.remove_node(key, self.builder.orphans);
+            self.tree.stamps.remove(key);
+            if let Some(wd) = self.watches.remove(&self.fs_path(key)) {
+                self.dirs.remove(&wd);
+                // Fails if the kernel already dropped the watch of a deleted directory.
+                let _ = self.inotify.watches().remove(wd);
+            }
+        }
+        removed
+    }
+
+    /// Re-maps `path` if its stamp changed, returning the changes.
+    fn update(&mut self, path: &Path) -> Result<Vec<ChangeEvent>, WalkTreeError> {
+        let key = self.builder.key(path);
+        let (Some(&node_id), Some(depth)) = (self.tree.map.get_by_left(&key), self.depth(path))
+        else {
+            return Ok(Vec::new());
+        };
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return Ok(Vec::new());
+        };
+        let entry = DirEntry::new(
+            path.to_path_buf(),
+            metadata.file_type().into(),
+            depth,
+            false,
+            None,
+        );
+        let stamp = Stamp::of(&entry);
+        let is_dir = entry.file_type().is_dir();
+        if stamp.is_some() && self.tree.stamps.get(&key) == stamp.as_ref() {
+            return Ok(Vec::new());
+        }
+        let mut nodes =
+            self.builder
+                .apply_map_fn(vec![entry], self.pool.as_ref(), &mut self.tree.errors)?;
+        // The mapper failed this time; leave out what a fresh walk would.
+        let Some(node) = nodes.pop() else {
+            let removed = match self.builder.map_error_action {
+                MapErrorAction::SkipSubtree => self.remove(path),
+                _ => {
+                    self.tree.remove_node(&key, self.builder.orphans);
+                    self.tree.stamps.remove(&key);
+                    vec![key]
+                }
+            };
+            return Ok(removed.into_iter().map(ChangeEvent::Removed).collect());
+        };
+        *self.tree.arena[node_id].get_mut() = node.data;
+        if is_dir {
+            self.tree.dirs.insert(key.clone());
+        } else {
+            self.tree.dirs.remove(&key);
+        }
+        match stamp {
+            Some(stamp) => self.tree.stamps.insert(key.clone(), stamp),
+            None => self.tree.stamps.remove(&key),
+        };
+        Ok(vec![ChangeEvent::Modified(key)])
+    }
+
+    /// Reconciles the whole tree with the filesystem after the kernel dropped events.
+    fn resync(&mut self, changes: &mut Vec<ChangeEvent>) -> Result<(), WalkTreeError> {
+        let keys = self.tree.map.left_values().cloned().collect::<Vec<_>>();
+        for key in keys {
+            let path = self.fs_path(&key);
+            if fs::symlink_metadata(&path).is_err() {
+                let removed = self.remove(&path);
+                changes.extend(removed.into_iter().map(ChangeEvent::Removed));
+            } else {
+                changes.extend(self.update(&path)?);
+            }
+        }
+        let dirs = self.watches.keys().cloned().collect::<Vec<_>>();
+        for dir in dirs {
+            let Ok(read_dir) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for child in read_dir.filter_map(Result::ok) {
+                let created = self.insert(&child.path())?;
+                changes.extend(created.into_iter().map(ChangeEvent::Created));
+            }
+        }
+        Ok(())
+    }
+
+    fn add_watch(&mut self, path: &Path) -> Result<(), WalkTreeError> {
+        let is_dir = fs::symlink_metadata(path).is_ok_and(|m| m.is_dir());
+        if !is_dir || self.depth(path).is_none_or(|d| d >= self.max_depth) {
+            return Ok(());
+        }
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::DONT_FOLLOW;
+        match self.inotify.watches().add(path, mask) {
+            Ok(wd) => {
+                self.dirs.insert(wd.clone(), path.to_path_buf());
+                self.watches.insert(path.to_path_buf(), wd);
+                Ok(())
+            }
+            Err(source) => self.builder.error_policy.handle(
+                WalkTreeError::Io {
+                    path: Some(path.to_path_buf()),
+                    depth: self.depth(path).unwrap_or(0),
+                    source,
+                },
+                &mut self.tree.errors,
+            ),
+        }
+    }
+
+    /// The path on disk of the node at `key`.
+    fn fs_path(&self, key: &Path) -> PathBuf {
+        if key.as_os_str().is_empty() {
+            self.builder.root_dir.clone()
+        } else {
+            self.builder.root_dir.join(key)
+        }
+    }
+
+    fn depth(&self, path: &Path) -> Option<usize> {
+        path.strip_prefix(&self.builder.root_dir)
+            .ok()
+            .map(|relative| relative.components().count())
+    }
+
+    fn io_error(&self, source: io::Error) -> WalkTreeError {
+        WalkTreeError::Io {
+            path: Some(self.builder.root_dir.clone()),
+            depth: 0,
+            source,
+        }
+    }
+}
+
+impl From<inotify::Event<&std::ffi::OsStr>> for RawEvent {
+    fn from(event: inotify::Event<&std::ffi::OsStr>) -> Self {
+        RawEvent {
+            wd: event.wd,
+            mask: event.mask,
+            cookie: event.cookie,
+            name: event.name.map(OsString::from),
+        }
+    }
+}
@@ -0,0 +1,68 @@
+#![cfg(feature = "cli")]
+
+use std::{fs, process::Command};
+use tempfile::tempdir;
+
+fn walktree(args: &[&str]) -> (String, bool) {
+    let output = Command::new(env!("CARGO_BIN_EXE_walktree"))
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        String::from_utf8(output.stdout).unwrap(),
+        output.status.success(),
+    )
+}
+
+#[test]
+fn formats_and_flags() {
+    let dir = tempdir().unwrap();
+    let root = dir.path();
+    fs::create_dir_all(root.join("b/c")).unwrap();
+    fs::write(root.join("a.rs"), "").unwrap();
+    fs::write(root.join("b/x.rs"), "").unwrap();
+    fs::write(root.join("b/c/y.txt"), "").unwrap();
+    let path = root.to_str().unwrap();
+
+    let (text, ok) = walktree(&[path, "--sort", "name", "--ascii", "-L", "1"]);
+    assert!(ok);
+    assert_eq!(
+        text,
+        format!("{path}\n|-- a.rs\n`-- b\n\n1 directory, 1 file\n")
+    );
+
+    let (ndjson, _) = walktree(&[path, "-f", "ndjson", "--sort", "name", "-e", "**/*.txt"]);
+    let paths = ndjson
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["path"].clone())
+        .collect::<Vec<_>>();
+    let expected =
+        ["a.rs", "b", "b/c", "b/x.rs"].map(|p| root.join(p).to_str().unwrap().to_string());
+    assert_eq!(paths[0], path);
+    assert_eq!(paths[1..], expected);
+
+    let (json, _) = walktree(&[path, "--format", "json", "--max-depth", "1"]);
+    let tree = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    assert_eq!(tree["nodes"][0]["children"].as_array().unwrap().len(), 2);
+
+    let (json, _) = walktree(&[path, "--format", "json", "--min-depth", "2"]);
+    let tree = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    assert_eq!(tree["nodes"].as_array().unwrap().len(), 2);
+    let (json, _) = walktree(&[
+        path,
+        "-f",
+        "json",
+        "--min-depth",
+        "2",
+        "--orphans",
+        "placeholder",
+    ]);
+    let tree = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+    assert_eq!(tree["nodes"].as_array().unwrap().len(), 1);
+
+    let (_, ok) = walktree(&[path, "--min-depth", "2", "--max-depth", "1"]);
+    assert!(!ok);
+
+    let (_, ok) = walktree(&[root.join("missing").to_str().unwrap()]);
+    assert!(!ok);
+}